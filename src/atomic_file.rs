@@ -0,0 +1,178 @@
+//! Replacing files without ever leaving them half written.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a temporary file of another process must have gone unmodified
+/// to be taken as left over, rather than one which is still being written.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60);
+
+/// A file which is written under a temporary name in the same directory,
+/// and only replaces the destination once [`AtomicFile::commit`] is called,
+/// so that the destination is never left half written.
+///
+/// The temporary file is removed if it is dropped before then. A process which
+/// aborts does not drop anything, so the temporary files it left for the same
+/// destination are removed when another is created.
+#[derive(Debug)]
+pub struct AtomicFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    /// Where to keep the file which is replaced, if anything.
+    backup_path: Option<PathBuf>,
+    /// Whether to fail instead of replacing an existing file.
+    new: bool,
+    writer: BufWriter<File>,
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Create the temporary file next to `path`, with the permissions of `path`
+    /// if it exists already. If `path` is a symbolic link, the file it links to
+    /// is replaced instead, so that the link is kept.
+    ///
+    /// Set `new` to fail on commit if `path` exists by then.
+    /// Set `backup` to keep the file at `path` when it is replaced,
+    /// with the suffix appended to its name.
+    pub fn create(path: &Path, new: bool, backup: Option<&str>) -> io::Result<Self> {
+        let is_symlink = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink());
+        let path = if is_symlink && !new {
+            fs::canonicalize(path)?
+        } else {
+            path.to_owned()
+        };
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        let with_name = |prefix: &str, suffix: &str| {
+            let mut name = OsString::from(prefix);
+            name.push(file_name);
+            name.push(suffix);
+            path.with_file_name(name)
+        };
+        remove_stale_temp_files(&path, file_name)?;
+        let temp_path = with_name(".", &format!(".{}.tmp", std::process::id()));
+        let backup_path = backup.map(|suffix| with_name("", suffix));
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        let atomic = Self {
+            path,
+            temp_path,
+            backup_path,
+            new,
+            writer: BufWriter::new(file),
+            committed: false,
+        };
+        // The temporary file is removed on drop if this fails.
+        match fs::metadata(&atomic.path) {
+            Ok(metadata) => atomic
+                .writer
+                .get_ref()
+                .set_permissions(metadata.permissions())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(atomic)
+    }
+
+    /// Flush and sync the temporary file to disk, keep a backup of the destination
+    /// if asked to, then move the temporary file to the destination and sync the
+    /// directory, so that the new file is there even if the system crashes.
+    ///
+    /// If the file is `new`, it is linked to the destination instead of renamed,
+    /// which fails if the destination exists without replacing it.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        if self.new {
+            fs::hard_link(&self.temp_path, &self.path)?;
+            fs::remove_file(&self.temp_path)?;
+        } else {
+            if let Some(backup_path) = &self.backup_path {
+                match fs::copy(&self.path, backup_path) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            fs::rename(&self.temp_path, &self.path)?;
+        }
+        self.committed = true;
+        sync_parent_dir(&self.path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+// Remove the temporary files for `path` which this process left before it was
+// started again under the same ID, or which other processes left long enough ago.
+fn remove_stale_temp_files(path: &Path, file_name: &OsStr) -> io::Result<()> {
+    let Some(file_name) = file_name.to_str() else {
+        return Ok(());
+    };
+    let prefix = format!(".{file_name}.");
+    for entry in fs::read_dir(parent_dir(path))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix)?.strip_suffix(".tmp"))
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let stale = pid == std::process::id()
+            || entry
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age >= STALE_TEMP_AGE);
+        if stale {
+            match fs::remove_file(entry.path()) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+// Directories cannot be opened as files to sync them on every platform.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    File::open(parent_dir(path))?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! Comparing a lock to what pruning makes of it, so that a lock which was
+//! updated without being pruned again can be rejected.
+
+use std::fmt;
+
+use crate::flake_lock::LockFile;
+use crate::plan::PlannedEdge;
+
+/// A difference between two locks, with nodes referenced by their index.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// An input which was added, removed, or references something else.
+    Edge {
+        /// The index of the node which owns the input.
+        node: String,
+        input: String,
+        /// The input path from the root to the input in the first lock,
+        /// if the node is reachable.
+        path: Option<Vec<String>>,
+        old: Option<PlannedEdge>,
+        new: Option<PlannedEdge>,
+    },
+    /// A node which only the first lock has.
+    RemovedNode {
+        node: String,
+        /// The URL of the node's `original` flake reference.
+        source: Option<String>,
+    },
+    /// A node which only the second lock has.
+    AddedNode {
+        node: String,
+        /// The URL of the node's `original` flake reference.
+        source: Option<String>,
+    },
+}
+
+/// Every input and node which differs between `before` and `after`, comparing nodes
+/// by their index. Inputs are listed in the order of the nodes of `before`,
+/// followed by the removed and then the added nodes, whose inputs are not listed.
+pub fn diff_locks(before: &LockFile, after: &LockFile) -> Vec<Change> {
+    let input_paths = before.input_paths();
+    let source = |lock: &LockFile, index: &str| {
+        let node = lock.get_node(lock.find_node(index)?)?.as_locked()?;
+        Some(node.original().to_string())
+    };
+
+    let mut changes = Vec::new();
+    let mut removed = Vec::new();
+    for id in before.node_ids() {
+        let index = before.node_index(id);
+        let old_node = before.get_node(id).unwrap();
+        let Some(new_node) = after.find_node(index).and_then(|id| after.get_node(id)) else {
+            removed.push(Change::RemovedNode {
+                node: index.to_owned(),
+                source: source(before, index),
+            });
+            continue;
+        };
+        let old_edges = old_node
+            .iter_edges()
+            .map(|(input, edge)| (input, Some(edge), new_node.get_edge(input)));
+        let new_edges = new_node
+            .iter_edges()
+            .filter(|(input, _)| old_node.get_edge(input).is_none())
+            .map(|(input, edge)| (input, None, Some(edge)));
+        for (input, old, new) in old_edges.chain(new_edges) {
+            let old = old.map(|edge| PlannedEdge::new(before, edge));
+            let new = new.map(|edge| PlannedEdge::new(after, edge));
+            if old == new {
+                continue;
+            }
+            changes.push(Change::Edge {
+                node: index.to_owned(),
+                input: input.to_owned(),
+                path: input_paths
+                    .get(&id)
+                    .map(|path| [path.as_slice(), &[input.to_owned()]].concat()),
+                old,
+                new,
+            });
+        }
+    }
+    changes.extend(removed);
+    changes.extend(
+        after
+            .node_indices()
+            .filter(|index| before.find_node(index).is_none())
+            .map(|index| Change::AddedNode {
+                node: index.to_owned(),
+                source: source(after, index),
+            }),
+    );
+    changes
+}
+
+/// The change as a line of a compact diff, such as
+/// `~ 'hyprcursor/nixpkgs': 'nixpkgs_2' -> follows 'nixpkgs'`.
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Edge {
+                node,
+                input,
+                path,
+                old,
+                new,
+            } => {
+                let path = match path {
+                    Some(path) => path.join("/"),
+                    None => format!("{node}/{input}"),
+                };
+                match (old, new) {
+                    (Some(old), Some(new)) => write!(f, "~ '{path}': {old} -> {new}"),
+                    (None, Some(new)) => write!(f, "+ '{path}': {new}"),
+                    (Some(old), None) => write!(f, "- '{path}': {old}"),
+                    (None, None) => write!(f, "  '{path}'"),
+                }
+            }
+            Self::RemovedNode { node, source } => write_node(f, '-', node, source),
+            Self::AddedNode { node, source } => write_node(f, '+', node, source),
+        }
+    }
+}
+
+fn write_node(
+    f: &mut fmt::Formatter<'_>,
+    sign: char,
+    node: &str,
+    source: &Option<String>,
+) -> fmt::Result {
+    match source {
+        Some(source) => write!(f, "{sign} node '{node}' ({source})"),
+        None => write!(f, "{sign} node '{node}'"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prune::{prune_orphan_nodes, substitute_flake_inputs_with_follows};
+    use crate::testing::*;
+
+    #[test]
+    fn check_hyprland_flake_lock() {
+        let original = read_sample(HYPRLAND_LOCK_NO_FOLLOWS);
+        let mut pruned = original.clone();
+        substitute_flake_inputs_with_follows(&mut pruned, &Default::default());
+        prune_orphan_nodes(&mut pruned);
+
+        let changes = diff_locks(&original, &pruned);
+        let lines = changes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert!(
+            lines.contains(&"~ 'hyprcursor/nixpkgs': 'nixpkgs_2' -> follows 'nixpkgs'".to_owned())
+        );
+        assert!(
+            lines.contains(&"- node 'nixpkgs_2' (github:NixOS/nixpkgs/nixos-unstable)".to_owned())
+        );
+        assert!(!changes
+            .iter()
+            .any(|change| matches!(change, Change::AddedNode { .. })));
+        assert_eq!(
+            diff_locks(&pruned, &original).len(),
+            changes.len(),
+            "the diff should be symmetric"
+        );
+        assert!(diff_locks(&pruned, &pruned).is_empty());
+    }
+}
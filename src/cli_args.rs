@@ -1,9 +1,11 @@
 use std::convert::Infallible;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter, Read, StdinLock, StdoutLock, Write};
+use std::io::{self, BufReader, Read, StdinLock, StdoutLock, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use allfollow::{AtomicFile, InputPattern};
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Input {
     Stdin,
@@ -17,6 +19,17 @@ pub enum Output {
     File(PathBuf),
 }
 
+/// How `prune --plan` writes the plan.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanFormat {
+    Json,
+    Text,
+}
+
+/// A comma separated list of input patterns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatternList(pub Vec<InputPattern>);
+
 #[derive(Debug)]
 pub enum InputReader<'a> {
     Stdin(StdinLock<'a>),
@@ -26,7 +39,7 @@ pub enum InputReader<'a> {
 #[derive(Debug)]
 pub enum OutputWriter<'a> {
     Stdout(StdoutLock<'a>),
-    File(BufWriter<File>),
+    File(AtomicFile),
 }
 
 impl Input {
@@ -39,7 +52,7 @@ impl Input {
         }
     }
 
-    pub fn open(&self) -> io::Result<InputReader> {
+    pub fn open(&self) -> io::Result<InputReader<'_>> {
         match self {
             Self::Stdin => Ok(InputReader::Stdin(io::stdin().lock())),
             Self::File(path) => Ok(InputReader::File(BufReader::new(File::open(path)?))),
@@ -57,19 +70,24 @@ impl Output {
         }
     }
 
-    // Create or open a file for writing.
+    // Create a file for writing, which replaces any existing file
+    // once the writer is finished, see `OutputWriter::finish`.
     // Set `new` if you would like to error if the file already exists.
-    pub fn create(&self, new: bool) -> io::Result<OutputWriter> {
+    // Set `backup` to keep the replaced file, with the suffix appended to its name.
+    pub fn create(&self, new: bool, backup: Option<&str>) -> io::Result<OutputWriter<'_>> {
         match self {
             Self::Stdout => Ok(OutputWriter::Stdout(io::stdout().lock())),
-            Self::File(path) => Ok(OutputWriter::File(BufWriter::new(
-                File::options()
-                    .write(true)
-                    .create(!new)
-                    .truncate(!new)
-                    .create_new(new)
-                    .open(path)?,
-            ))),
+            Self::File(path) => AtomicFile::create(path, new, backup).map(OutputWriter::File),
+        }
+    }
+}
+
+impl OutputWriter<'_> {
+    /// Flush everything written, and replace the destination file if there is one.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Stdout(mut lock) => lock.flush(),
+            Self::File(file) => file.commit(),
         }
     }
 }
@@ -102,6 +120,31 @@ impl FromStr for Output {
     }
 }
 
+impl FromStr for PatternList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl FromStr for PlanFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "text" => Ok(Self::Text),
+            _ => Err(format!(
+                "unknown plan format '{s}', expected one of: json, text"
+            )),
+        }
+    }
+}
+
 impl From<Input> for Output {
     fn from(value: Input) -> Self {
         match value {
@@ -135,3 +178,153 @@ impl<'a> Write for OutputWriter<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let temp_dir = std::env::temp_dir().join(format!("allfollow_test_{name}"));
+        if temp_dir.exists() {
+            fs::remove_dir_all(&temp_dir).unwrap();
+        }
+        fs::create_dir_all(&temp_dir).unwrap();
+        temp_dir
+    }
+
+    fn write(output: &Output, new: bool, backup: Option<&str>, text: &str) -> io::Result<()> {
+        let mut writer = output.create(new, backup)?;
+        writer.write_all(text.as_bytes())?;
+        writer.finish()
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn replace_file_in_place() {
+        let dir = temp_dir("replace_in_place");
+        let path = dir.join("flake.lock");
+        fs::write(&path, "old").unwrap();
+
+        write(&Output::from(&path), false, None, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(file_names(&dir), ["flake.lock"]);
+
+        let error = write(&Output::from(&path), true, None, "newer").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(file_names(&dir), ["flake.lock"]);
+
+        let other = dir.join("other.lock");
+        write(&Output::from(&other), true, None, "other").unwrap();
+        assert_eq!(fs::read_to_string(&other).unwrap(), "other");
+        assert_eq!(file_names(&dir), ["flake.lock", "other.lock"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_permissions_and_symlinks() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = temp_dir("keep_permissions");
+        let path = dir.join("flake.lock");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write(&Output::from(&path), false, None, "new").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        let link = dir.join("link.lock");
+        symlink("flake.lock", &link).unwrap();
+        write(&Output::from(&link), false, Some(".bak"), "linked").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "linked");
+        assert_eq!(
+            fs::read_to_string(dir.join("flake.lock.bak")).unwrap(),
+            "new"
+        );
+        assert_eq!(
+            file_names(&dir),
+            ["flake.lock", "flake.lock.bak", "link.lock"]
+        );
+    }
+
+    #[test]
+    fn keep_backups() {
+        let dir = temp_dir("keep_backups");
+        let path = dir.join("flake.lock");
+
+        // There is nothing to back up yet.
+        write(&Output::from(&path), false, Some(".bak"), "first").unwrap();
+        assert_eq!(file_names(&dir), ["flake.lock"]);
+
+        write(&Output::from(&path), false, Some(".bak"), "second").unwrap();
+        write(&Output::from(&path), false, Some(".orig"), "third").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third");
+        assert_eq!(
+            fs::read_to_string(dir.join("flake.lock.bak")).unwrap(),
+            "first"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("flake.lock.orig")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn remove_temporary_file_on_failure() {
+        let dir = temp_dir("remove_temporary_file");
+        let path = dir.join("flake.lock");
+        fs::write(&path, "old").unwrap();
+
+        let output = Output::from(&path);
+        let mut writer = output.create(false, None).unwrap();
+        writer.write_all(b"unfinished").unwrap();
+        drop(writer);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(file_names(&dir), ["flake.lock"]);
+
+        // A directory cannot be replaced by a file.
+        let subdir = dir.join("subdir");
+        fs::create_dir(&subdir).unwrap();
+        write(&Output::from(&subdir), false, None, "new").unwrap_err();
+        assert!(subdir.is_dir());
+        assert_eq!(file_names(&dir), ["flake.lock", "subdir"]);
+    }
+
+    /// Temporary files left by an aborted process are removed,
+    /// but not those which another process may still be writing.
+    #[test]
+    fn remove_stale_temporary_files() {
+        let dir = temp_dir("remove_stale_temporary_files");
+        let path = dir.join("flake.lock");
+        let temp = |pid: u32| dir.join(format!(".flake.lock.{pid}.tmp"));
+        fs::write(temp(std::process::id()), "aborted").unwrap();
+        let old = File::create(temp(1)).unwrap();
+        old.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(3600))
+            .unwrap();
+        drop(old);
+        fs::write(temp(2), "in progress").unwrap();
+        fs::write(dir.join(".other.lock.3.tmp"), "").unwrap();
+
+        write(&Output::from(&path), false, None, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(
+            file_names(&dir),
+            [".flake.lock.2.tmp", ".other.lock.3.tmp", "flake.lock"]
+        );
+    }
+}
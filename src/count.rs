@@ -0,0 +1,188 @@
+//! Reference counting over the node graph of a lock file.
+
+use std::iter::repeat;
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::flake_lock::{LockFile, NodeId};
+
+/// For every node in a lock, the number of paths leading to it from one node
+/// (usually the root), and the number of distinct nodes referencing it.
+///
+/// Dereferences to the path counts, keyed by node index.
+pub struct FlakeNodeVisits<'a> {
+    // Number of distinct paths leading to each node.
+    inner: IndexMap<&'a str, u64>,
+    // Number of distinct nodes with an input leading to each node.
+    parents: IndexMap<&'a str, u32>,
+    // Index of the node which this count is relative to.
+    root_index: &'a str,
+}
+
+impl<'a> FlakeNodeVisits<'a> {
+    /// Count the paths from the node `id` to every other node,
+    /// and the number of reachable nodes referencing each node.
+    ///
+    /// A single depth-first search orders the reachable nodes topologically,
+    /// then path counts are summed up over the edges in that order.
+    /// Inputs leading back to a node on the search path (cycles) do not add paths.
+    pub fn count_from_node(lock: &'a LockFile, id: NodeId) -> Self {
+        let mut edge_targets = IndexMap::new();
+        let mut stack = Vec::new();
+        if lock.get_node(id).is_some() {
+            edge_targets.insert(id, lock.edge_targets(id));
+            stack.push((id, 0));
+        }
+        // Nodes in the order that the search has finished with them.
+        let mut finished = IndexSet::new();
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+            if let Some(&target) = edge_targets[&node].get(*next) {
+                *next += 1;
+                if !edge_targets.contains_key(&target) {
+                    edge_targets.insert(target, lock.edge_targets(target));
+                    stack.push((target, 0));
+                }
+            } else {
+                finished.insert(node);
+                stack.pop();
+            }
+        }
+
+        let mut paths: IndexMap<_, _> = lock.node_ids().zip(repeat(0_u64)).collect();
+        let mut parents: IndexMap<_, _> = lock.node_ids().zip(repeat(0_u32)).collect();
+        if let Some(count) = paths.get_mut(&id) {
+            *count = 1;
+        }
+        for (rank, node) in finished.iter().enumerate().rev() {
+            let count = paths[node];
+            let targets = &edge_targets[node];
+            for (position, target) in targets.iter().enumerate() {
+                if !targets[..position].contains(target) {
+                    parents[target] += 1;
+                }
+                if finished.get_index_of(target).unwrap() < rank {
+                    paths[target] = paths[target].saturating_add(count);
+                }
+            }
+        }
+
+        FlakeNodeVisits {
+            inner: paths
+                .into_iter()
+                .map(|(id, count)| (lock.node_index(id), count))
+                .collect(),
+            parents: parents
+                .into_iter()
+                .map(|(id, count)| (lock.node_index(id), count))
+                .collect(),
+            root_index: lock.node_index(id),
+        }
+    }
+
+    pub fn parents(&self) -> &IndexMap<&'a str, u32> {
+        &self.parents
+    }
+
+    /// The index of the node which the counts are relative to.
+    pub fn root_index(&self) -> &'a str {
+        self.root_index
+    }
+
+    pub fn into_inner(self) -> IndexMap<&'a str, u64> {
+        self.inner
+    }
+}
+
+impl<'a> From<FlakeNodeVisits<'a>> for IndexMap<&'a str, u64> {
+    fn from(value: FlakeNodeVisits<'a>) -> Self {
+        value.into_inner()
+    }
+}
+
+impl<'a> std::ops::Deref for FlakeNodeVisits<'a> {
+    type Target = IndexMap<&'a str, u64>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<'a> std::ops::DerefMut for FlakeNodeVisits<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::*;
+
+    /// A lock of `layers` layers of `width` nodes each, named `n{i}_{layer}`,
+    /// where every node in a layer depends on every node of the next layer,
+    /// so the number of paths to a layer grows by a factor of `width` with each layer.
+    fn diamond_lock(layers: usize, width: usize) -> LockFile {
+        let inputs = |layer: usize| {
+            (0..width)
+                .filter(|_| layer + 1 < layers)
+                .map(|i| (format!("n{i}"), json!(format!("n{i}_{}", layer + 1))))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let mut nodes = serde_json::Map::new();
+        for layer in 0..layers {
+            for i in 0..width {
+                let mut node = github_node("r", inputs(layer).into());
+                node["locked"]["rev"] = layer.to_string().into();
+                nodes.insert(format!("n{i}_{layer}"), node);
+            }
+        }
+        let root_inputs = (0..width)
+            .map(|i| (format!("n{i}"), json!(format!("n{i}_0"))))
+            .collect::<serde_json::Map<_, _>>();
+        nodes.insert("root".into(), json!({"inputs": root_inputs}));
+        lock_of(nodes.into())
+    }
+
+    /// The number of paths doubles with each layer until it saturates.
+    #[test]
+    fn count_diamond_heavy_flake_lock() {
+        const LAYERS: usize = 66;
+        let lock = diamond_lock(LAYERS, 2);
+
+        let node_hits = FlakeNodeVisits::count_from_node(&lock, lock.root_id());
+
+        assert_eq!(node_hits.len(), 2 * LAYERS + 1);
+        assert_eq!(node_hits["n0_0"], 1);
+        assert_eq!(node_hits["n1_63"], 1 << 63);
+        assert_eq!(node_hits[&*format!("n1_{}", LAYERS - 1)], u64::MAX);
+        assert_eq!(node_hits.parents()["n0_0"], 1);
+        assert_eq!(node_hits.parents()["n1_1"], 2);
+    }
+
+    /// Enumerating each of the 4^2000 paths to the last layer would never finish,
+    /// while counting them takes time linear in the number of edges,
+    /// well within the bound even for an unoptimized build on a slow machine.
+    #[test]
+    fn count_diamond_heavy_flake_lock_runtime() {
+        const LAYERS: usize = 2000;
+        const WIDTH: usize = 4;
+        let lock = diamond_lock(LAYERS, WIDTH);
+
+        let start = Instant::now();
+        let node_hits = FlakeNodeVisits::count_from_node(&lock, lock.root_id());
+        let elapsed = start.elapsed();
+
+        assert_eq!(node_hits.len(), LAYERS * WIDTH + 1);
+        assert_eq!(node_hits[&*format!("n0_{}", LAYERS - 1)], u64::MAX);
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "counting {} edges took {elapsed:?}",
+            (LAYERS - 1) * WIDTH * WIDTH
+        );
+    }
+}
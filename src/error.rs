@@ -0,0 +1,239 @@
+//! The errors which can stop processing of a lock file,
+//! and the process exit codes which they correspond to.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::config::Markers;
+use crate::flake_lock::{LockIssue, MAX_SUPPORTED_LOCK_VERSION, MIN_SUPPORTED_LOCK_VERSION};
+use crate::journal::JournalError;
+use crate::plan::PlanError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file (or a standard stream) failed.
+    Io {
+        /// What was being done, such as `to read './flake.lock'`.
+        context: String,
+        source: io::Error,
+    },
+    /// The lock file is not valid JSON, or does not have the shape of a lock file.
+    Parse(serde_path_to_error::Error<serde_json::Error>),
+    /// The lock file is of a schema version this crate does not know how to handle.
+    UnsupportedVersion { version: u32 },
+    /// The node graph of the lock file cannot be traversed.
+    /// Only the fatal issues are kept, see [`LockIssue::is_fatal`],
+    /// unless harmless issues were asked to fail as well (`allfollow validate --strict`).
+    Integrity(Vec<LockIssue>),
+    /// The `flake.nix` could not be edited.
+    FlakeNix {
+        path: PathBuf,
+        kind: FlakeNixErrorKind,
+    },
+    /// The project settings are invalid, see [`crate::settings`].
+    Settings(SettingsError),
+    /// A plan could not be read, or does not apply to the lock file.
+    Plan(PlanError),
+    /// A journal could not be read, or does not restore to the lock file.
+    Journal(JournalError),
+    /// No node has the index, or is at the input path, which was asked about.
+    NodeNotFound { node: String },
+    /// Pruning would change the lock file, see [`crate::check`].
+    NotPruned { changes: usize },
+}
+
+/// Why a `flake.nix` could not be edited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FlakeNixErrorKind {
+    /// Either or both of the start and end markers are missing.
+    MissingMarkers(Markers),
+    /// The end marker comes before the start marker.
+    MisorderedMarkers,
+}
+
+/// A project setting which has an invalid value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettingsError {
+    /// Where the setting came from, such as `'./.allfollow.toml'`.
+    pub origin: String,
+    /// The offending key, such as `rules.exclude[0]` or `ALLFOLLOW_RESOLVE`,
+    /// or empty if the settings could not be parsed at all.
+    pub key: String,
+    pub message: String,
+}
+
+impl Error {
+    /// Exit code used when an I/O operation fails.
+    pub const EXIT_IO: i32 = 2;
+    /// Exit code used when the lock file cannot be parsed.
+    pub const EXIT_PARSE: i32 = 3;
+    /// Exit code used when the lock file version is not supported.
+    pub const EXIT_UNSUPPORTED_VERSION: i32 = 4;
+    /// Exit code used when the node graph of the lock file is broken.
+    pub const EXIT_INTEGRITY: i32 = 5;
+    /// Exit code used when the `flake.nix` cannot be edited.
+    pub const EXIT_FLAKE_NIX: i32 = 6;
+    /// Exit code used when the project settings are invalid.
+    pub const EXIT_SETTINGS: i32 = 7;
+    /// Exit code used when a plan cannot be read or applied.
+    pub const EXIT_PLAN: i32 = 8;
+    /// Exit code used when a node asked about is not in the lock file.
+    pub const EXIT_NODE_NOT_FOUND: i32 = 9;
+    /// Exit code used when the lock file is not fully pruned.
+    pub const EXIT_NOT_PRUNED: i32 = 10;
+    /// Exit code used when a journal cannot be read or restored.
+    pub const EXIT_JOURNAL: i32 = 11;
+
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        Self::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// The process exit code for this class of error.
+    ///
+    /// Exit code `1` is left for command line usage errors,
+    /// which are reported by the argument parser.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Io { .. } => Self::EXIT_IO,
+            Self::Parse(_) => Self::EXIT_PARSE,
+            Self::UnsupportedVersion { .. } => Self::EXIT_UNSUPPORTED_VERSION,
+            Self::Integrity(_) => Self::EXIT_INTEGRITY,
+            Self::FlakeNix { .. } => Self::EXIT_FLAKE_NIX,
+            Self::Settings(_) => Self::EXIT_SETTINGS,
+            Self::Plan(_) => Self::EXIT_PLAN,
+            Self::Journal(_) => Self::EXIT_JOURNAL,
+            Self::NodeNotFound { .. } => Self::EXIT_NODE_NOT_FOUND,
+            Self::NotPruned { .. } => Self::EXIT_NOT_PRUNED,
+        }
+    }
+}
+
+impl From<serde_path_to_error::Error<serde_json::Error>> for Error {
+    /// Failures of the underlying reader surface as JSON errors,
+    /// those are sorted back into [`Error::Io`].
+    fn from(error: serde_path_to_error::Error<serde_json::Error>) -> Self {
+        if error.inner().is_io() {
+            Self::io("to read the lock file", error.into_inner().into())
+        } else {
+            Self::Parse(error)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { context, source } => write!(f, "failed {context}: {source}"),
+            Self::Parse(error) => write!(f, "failed to parse the lock file: {error}"),
+            Self::UnsupportedVersion { version } => write!(
+                f,
+                "this program supports lock files between schema versions \
+                {MIN_SUPPORTED_LOCK_VERSION} and {MAX_SUPPORTED_LOCK_VERSION}, \
+                but the lock file is of version {version}"
+            ),
+            Self::Integrity(issues) if issues.iter().any(LockIssue::is_fatal) => write!(
+                f,
+                "the lock file has {} problem(s) which make its node graph impossible to traverse",
+                issues.len()
+            ),
+            Self::Integrity(issues) => write!(f, "the lock file has {} warning(s)", issues.len()),
+            Self::FlakeNix { path, kind } => {
+                write!(f, "cannot update '{}': {kind}", path.display())
+            }
+            Self::Settings(error) => write!(f, "{error}"),
+            Self::Plan(error) => write!(f, "{error}"),
+            Self::Journal(error) => write!(f, "{error}"),
+            Self::NodeNotFound { node } => write!(
+                f,
+                "the lock file has no node with the index or at the input path '{node}'"
+            ),
+            Self::NotPruned { changes } => write!(
+                f,
+                "the lock file is not fully pruned, pruning would make {changes} change(s)"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for FlakeNixErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingMarkers(markers) => write!(
+                f,
+                "could not find the start and end markers, please add them manually:\n{}\n{}",
+                markers.start, markers.end
+            ),
+            Self::MisorderedMarkers => write!(f, "the start marker comes after the end marker"),
+        }
+    }
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            origin,
+            key,
+            message,
+        } = self;
+        if key.is_empty() {
+            write!(f, "invalid settings in {origin}: {message}")
+        } else {
+            write!(f, "invalid setting '{key}' in {origin}: {message}")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Parse(error) => Some(error),
+            Self::UnsupportedVersion { .. }
+            | Self::Integrity(_)
+            | Self::FlakeNix { .. }
+            | Self::Settings(_)
+            | Self::Plan(_)
+            | Self::Journal(_)
+            | Self::NodeNotFound { .. }
+            | Self::NotPruned { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every exit code has a row in the table of the README, and every row
+    /// but those of success and usage errors is an exit code of an error.
+    #[test]
+    fn exit_codes_are_documented() {
+        let readme = std::fs::read_to_string("README.md").unwrap();
+        let documented = readme
+            .lines()
+            .skip_while(|line| *line != "# Exit codes")
+            .filter_map(|line| line.strip_prefix("| ")?.split_once(" | "))
+            .filter_map(|(code, _)| code.trim().parse::<i32>().ok())
+            .collect::<Vec<_>>();
+        let mut codes = vec![0, 1];
+        codes.extend([
+            Error::EXIT_IO,
+            Error::EXIT_PARSE,
+            Error::EXIT_UNSUPPORTED_VERSION,
+            Error::EXIT_INTEGRITY,
+            Error::EXIT_FLAKE_NIX,
+            Error::EXIT_SETTINGS,
+            Error::EXIT_PLAN,
+            Error::EXIT_NODE_NOT_FOUND,
+            Error::EXIT_NOT_PRUNED,
+            Error::EXIT_JOURNAL,
+        ]);
+        assert_eq!(documented, codes);
+    }
+}
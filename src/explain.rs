@@ -0,0 +1,158 @@
+//! Explaining why a node was kept or removed by pruning,
+//! by the inputs which referenced it before and after.
+
+use crate::flake_lock::{LockFile, Node, NodeEdge, NodeId};
+use crate::prune::Substitution;
+
+/// An input of a node reachable from the root, which references another node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    /// The node which owns the input.
+    pub node: NodeId,
+    pub input: String,
+    /// The shortest input path from the root to the input.
+    pub path: Vec<String>,
+}
+
+/// Why a node was kept or removed, see [`explain_node`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Explanation {
+    pub node: NodeId,
+    /// The inputs which referenced the node before pruning.
+    pub references_before: Vec<Reference>,
+    /// The inputs which reference the node after pruning,
+    /// which are what kept it alive if it was not removed.
+    pub references_after: Vec<Reference>,
+    /// The substitutions of inputs which referenced the node before,
+    /// or which were redirected to it, in the order they were made.
+    pub substitutions: Vec<Substitution>,
+    /// Whether the node was removed, because no input references it after pruning.
+    pub removed: bool,
+}
+
+impl Explanation {
+    /// The substitution which was last made to the input of `reference`.
+    pub fn substitution_of(&self, reference: &Reference) -> Option<&Substitution> {
+        self.substitutions
+            .iter()
+            .rev()
+            .find(|s| s.node == reference.node && s.input == reference.input)
+    }
+}
+
+/// Find the node referenced by `target`, either a node index
+/// or an input path from the root separated by `/`.
+pub fn find_node_or_path(lock: &LockFile, target: &str) -> Option<NodeId> {
+    lock.find_node(target)
+        .or_else(|| lock.follow_path(target.split('/')))
+        .filter(|&id| lock.get_node(id).is_some())
+}
+
+/// Explain what happened to the node `id` between `before` and `after`,
+/// where `after` is a pruned clone of `before`, by the `substitutions`
+/// and `removed` nodes which were made to it in order.
+pub fn explain_node(
+    before: &LockFile,
+    after: &LockFile,
+    substitutions: &[Substitution],
+    removed: &[(NodeId, Node)],
+    id: NodeId,
+) -> Explanation {
+    let references_to = |edge: &NodeEdge, lock: &LockFile| lock.resolve_edge(edge) == Some(id);
+    let substitutions = substitutions
+        .iter()
+        .filter(|s| {
+            references_to(&s.old, before)
+                || s.new.as_ref().is_some_and(|new| references_to(new, after))
+        })
+        .cloned()
+        .collect();
+    Explanation {
+        node: id,
+        references_before: references(before, id),
+        references_after: references(after, id),
+        substitutions,
+        removed: removed.iter().any(|(removed, _)| *removed == id),
+    }
+}
+
+/// Every input of a node reachable from the root which references the node `id`.
+pub fn references(lock: &LockFile, id: NodeId) -> Vec<Reference> {
+    let paths = lock.input_paths();
+    let mut references = Vec::new();
+    for (&parent, path) in &paths {
+        for (input, edge) in lock.get_node(parent).unwrap().iter_edges() {
+            if lock.resolve_edge(edge) == Some(id) {
+                references.push(Reference {
+                    node: parent,
+                    input: input.to_owned(),
+                    path: [path.as_slice(), &[input.to_owned()]].concat(),
+                });
+            }
+        }
+    }
+    references
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prune::{
+        prune_orphan_nodes, substitute_flake_inputs_with_follows, Reason, SubstituteOptions,
+    };
+    use crate::rules::FollowRules;
+    use crate::testing::*;
+
+    #[test]
+    fn explain_hyprland_flake_lock() {
+        let original = read_sample(HYPRLAND_LOCK_NO_FOLLOWS);
+        let mut pruned = original.clone();
+        let options = SubstituteOptions {
+            rules: FollowRules {
+                exclude: vec!["hyprcursor/hyprlang".parse().unwrap()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let substitutions = substitute_flake_inputs_with_follows(&mut pruned, &options);
+        let removed = prune_orphan_nodes(&mut pruned);
+        let explain = |target: &str| {
+            let id = find_node_or_path(&original, target).unwrap();
+            explain_node(&original, &pruned, &substitutions, &removed, id)
+        };
+
+        let explanation = explain("nixpkgs_2");
+        assert!(explanation.removed);
+        assert_eq!(explanation.references_before.len(), 3);
+        assert!(explanation.references_after.is_empty());
+        let redirected = &explanation.references_before[0];
+        assert_eq!(redirected.path, ["hyprcursor", "nixpkgs"]);
+        assert_eq!(
+            explanation.substitution_of(redirected).unwrap().reason,
+            Reason::SameName
+        );
+
+        let explanation = explain("hyprcursor/hyprlang");
+        assert!(!explanation.removed);
+        let [kept] = explanation.references_after.as_slice() else {
+            panic!(
+                "expected one reference, got {:?}",
+                explanation.references_after
+            );
+        };
+        assert_eq!(kept.path, ["hyprcursor", "hyprlang"]);
+        assert!(matches!(
+            explanation.substitution_of(kept),
+            Some(Substitution {
+                new: None,
+                reason: Reason::Excluded(_),
+                ..
+            })
+        ));
+
+        let explanation = explain("hyprlang_3");
+        assert_eq!(explanation.references_after[0].node, pruned.root_id());
+        assert!(explanation.references_after.len() > 1);
+        assert!(find_node_or_path(&original, "hyprcursor/nope").is_none());
+    }
+}
@@ -1,7 +1,10 @@
-use std::cell::{Ref, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
 
 use indexmap::IndexMap;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::error::{Error, Result};
 
 pub const MAX_SUPPORTED_LOCK_VERSION: u32 = 7;
 pub const MIN_SUPPORTED_LOCK_VERSION: u32 = 5;
@@ -10,104 +13,612 @@ fn default_true() -> bool {
     true
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-#[serde(deny_unknown_fields, rename_all = "camelCase")]
+/// A `flake.lock` with its nodes kept in an arena, addressed by [`NodeId`].
+///
+/// Node indices (the keys of `nodes` in the JSON) only exist at the serde boundary
+/// and for display, edges between nodes reference the arena directly.
+#[derive(Clone, Debug, PartialEq)]
 pub struct LockFile {
-    nodes: IndexMap<String, RefCell<Node>>,
-    root: String,
+    // Every node index that has been seen, in the order of the lock file.
+    // A slot is vacant if its node was removed, or if the index was referenced
+    // by an edge or as the root, but never had a node.
+    slots: Vec<Slot>,
+    ids: HashMap<String, NodeId>,
+    root: NodeId,
     version: u32,
 }
 
+/// A handle to a node in the arena of a [`LockFile`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Slot {
+    index: String,
+    node: Option<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeEdge {
+    Indexed(NodeId),
+    Follows(Vec<String>),
+}
+
+/// A node edge as it is written in the lock file, referencing nodes by index.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, untagged)]
-pub enum NodeEdge {
+pub enum RawNodeEdge {
     Indexed(String),
     Follows(Vec<String>),
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct RawLockFile {
+    nodes: IndexMap<String, Node<RawNodeEdge>>,
+    root: String,
+    version: u32,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, untagged)]
-pub enum Node {
-    Locked(LockedNode),
-    Unlocked(UnlockedNode),
+#[expect(clippy::large_enum_variant)]
+pub enum Node<E = NodeEdge> {
+    Locked(LockedNode<E>),
+    Unlocked(UnlockedNode<E>),
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
-pub struct LockedNode {
+#[serde(bound(deserialize = "E: Deserialize<'de>"))]
+pub struct LockedNode<E = NodeEdge> {
     #[serde(skip_serializing_if = "Clone::clone", default = "default_true")]
     flake: bool,
     #[serde(skip_serializing_if = "IndexMap::is_empty", default)]
-    inputs: IndexMap<String, RefCell<NodeEdge>>,
-    locked: serde_json::Value,
-    original: serde_json::Value,
+    inputs: IndexMap<String, E>,
+    locked: FlakeRef,
+    original: FlakeRef,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
-pub struct UnlockedNode {
-    inputs: IndexMap<String, RefCell<NodeEdge>>,
+pub struct UnlockedNode<E = NodeEdge> {
+    inputs: IndexMap<String, E>,
 }
 
-impl NodeEdge {
-    pub fn index(&self) -> Option<&str> {
+/// The fetcher named by the `type` attribute of a flake reference.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FlakeRefType {
+    GitHub,
+    GitLab,
+    SourceHut,
+    Git,
+    Mercurial,
+    Path,
+    Tarball,
+    File,
+    Indirect,
+    /// A fetcher this program does not know about, kept verbatim.
+    Other(String),
+}
+
+/// A flake reference as it appears in the `locked` and `original`
+/// attributes of a lock node.
+///
+/// Attributes which are not modeled here, or which have an unexpected type,
+/// are kept in `attrs` so that the reference serializes exactly as it was read.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Map<String, Value>", into = "Map<String, Value>")]
+pub struct FlakeRef {
+    pub r#type: FlakeRefType,
+    pub owner: Option<String>,
+    pub repo: Option<String>,
+    pub host: Option<String>,
+    pub url: Option<String>,
+    pub path: Option<String>,
+    pub id: Option<String>,
+    pub r#ref: Option<String>,
+    pub rev: Option<String>,
+    pub dir: Option<String>,
+    pub nar_hash: Option<String>,
+    pub last_modified: Option<u64>,
+    pub rev_count: Option<u64>,
+    pub attrs: Map<String, Value>,
+}
+
+impl FlakeRefType {
+    pub fn as_str(&self) -> &str {
         match self {
-            Self::Indexed(index) => Some(index.as_str()),
-            _ => None,
+            Self::GitHub => "github",
+            Self::GitLab => "gitlab",
+            Self::SourceHut => "sourcehut",
+            Self::Git => "git",
+            Self::Mercurial => "hg",
+            Self::Path => "path",
+            Self::Tarball => "tarball",
+            Self::File => "file",
+            Self::Indirect => "indirect",
+            Self::Other(other) => other,
         }
     }
+}
 
-    pub fn path(&self) -> Option<&Vec<String>> {
-        match self {
-            Self::Follows(path) => Some(path),
-            _ => None,
+impl From<&str> for FlakeRefType {
+    fn from(value: &str) -> Self {
+        match value {
+            "github" => Self::GitHub,
+            "gitlab" => Self::GitLab,
+            "sourcehut" => Self::SourceHut,
+            "git" => Self::Git,
+            "hg" => Self::Mercurial,
+            "path" => Self::Path,
+            "tarball" => Self::Tarball,
+            "file" => Self::File,
+            "indirect" => Self::Indirect,
+            other => Self::Other(other.to_owned()),
         }
     }
 }
 
-impl std::fmt::Display for NodeEdge {
+impl std::fmt::Display for FlakeRefType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Indexed(index) => write!(f, "{index}"),
-            Self::Follows(path) => {
-                let mut path = path.iter().peekable();
-                while let Some(name) = path.next() {
-                    write!(f, "{name}")?;
-                    if path.peek().is_some() {
-                        write!(f, "/")?;
+        f.write_str(self.as_str())
+    }
+}
+
+impl FlakeRef {
+    pub fn new(r#type: FlakeRefType) -> Self {
+        Self {
+            r#type,
+            owner: None,
+            repo: None,
+            host: None,
+            url: None,
+            path: None,
+            id: None,
+            r#ref: None,
+            rev: None,
+            dir: None,
+            nar_hash: None,
+            last_modified: None,
+            rev_count: None,
+            attrs: Map::new(),
+        }
+    }
+
+    /// Whether both references point at the same repository (and subdirectory),
+    /// regardless of the branch, tag or revision.
+    ///
+    /// References of different types or subdirectories are never the same source,
+    /// otherwise see [`FlakeRef::same_repository`].
+    pub fn same_source(&self, other: &FlakeRef) -> bool {
+        self.r#type == other.r#type && self.dir == other.dir && self.same_repository(other)
+    }
+
+    /// Whether the `other` reference names the same repository as this one,
+    /// comparing only the attributes which identify a repository for the type of this one.
+    ///
+    /// Forges are compared by host, owner and repository, ignoring ASCII case
+    /// as the forges themselves do. Indirect references are compared by their ID,
+    /// and everything else by URL and path. References which do not identify
+    /// any repository are never the same repository.
+    pub fn same_repository(&self, other: &FlakeRef) -> bool {
+        fn eq_ignore_case(a: &Option<String>, b: &Option<String>) -> bool {
+            match (a, b) {
+                (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+                (None, None) => true,
+                _ => false,
+            }
+        }
+        match self.r#type {
+            FlakeRefType::GitHub | FlakeRefType::GitLab | FlakeRefType::SourceHut => {
+                self.owner.is_some()
+                    && self.repo.is_some()
+                    && eq_ignore_case(&self.host, &other.host)
+                    && eq_ignore_case(&self.owner, &other.owner)
+                    && eq_ignore_case(&self.repo, &other.repo)
+            }
+            FlakeRefType::Indirect => self.id.is_some() && self.id == other.id,
+            _ => {
+                (self.url.is_some() || self.path.is_some())
+                    && self.url == other.url
+                    && self.path == other.path
+            }
+        }
+    }
+}
+
+// Attributes which do not have the expected JSON type are put back,
+// and will be serialized untouched from `FlakeRef::attrs`.
+fn take_string(attrs: &mut Map<String, Value>, key: &str) -> Option<String> {
+    match attrs.remove(key)? {
+        Value::String(value) => Some(value),
+        other => {
+            attrs.insert(key.to_owned(), other);
+            None
+        }
+    }
+}
+
+fn take_u64(attrs: &mut Map<String, Value>, key: &str) -> Option<u64> {
+    match attrs.remove(key)? {
+        Value::Number(number) if number.is_u64() => number.as_u64(),
+        other => {
+            attrs.insert(key.to_owned(), other);
+            None
+        }
+    }
+}
+
+impl TryFrom<Map<String, Value>> for FlakeRef {
+    type Error = String;
+
+    fn try_from(mut attrs: Map<String, Value>) -> Result<Self, Self::Error> {
+        let r#type = take_string(&mut attrs, "type")
+            .ok_or("flake reference is missing the string attribute `type`")?;
+        Ok(Self {
+            r#type: FlakeRefType::from(r#type.as_str()),
+            owner: take_string(&mut attrs, "owner"),
+            repo: take_string(&mut attrs, "repo"),
+            host: take_string(&mut attrs, "host"),
+            url: take_string(&mut attrs, "url"),
+            path: take_string(&mut attrs, "path"),
+            id: take_string(&mut attrs, "id"),
+            r#ref: take_string(&mut attrs, "ref"),
+            rev: take_string(&mut attrs, "rev"),
+            dir: take_string(&mut attrs, "dir"),
+            nar_hash: take_string(&mut attrs, "narHash"),
+            last_modified: take_u64(&mut attrs, "lastModified"),
+            rev_count: take_u64(&mut attrs, "revCount"),
+            attrs,
+        })
+    }
+}
+
+impl From<FlakeRef> for Map<String, Value> {
+    fn from(value: FlakeRef) -> Self {
+        let mut attrs = value.attrs;
+        let strings = [
+            ("type", Some(value.r#type.as_str().to_owned())),
+            ("owner", value.owner),
+            ("repo", value.repo),
+            ("host", value.host),
+            ("url", value.url),
+            ("path", value.path),
+            ("id", value.id),
+            ("ref", value.r#ref),
+            ("rev", value.rev),
+            ("dir", value.dir),
+            ("narHash", value.nar_hash),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                attrs.insert(key.to_owned(), Value::String(value));
+            }
+        }
+        let numbers = [
+            ("lastModified", value.last_modified),
+            ("revCount", value.rev_count),
+        ];
+        for (key, value) in numbers {
+            if let Some(value) = value {
+                attrs.insert(key.to_owned(), Value::from(value));
+            }
+        }
+        attrs
+    }
+}
+
+/// The error returned when a string is not a valid flake reference URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseFlakeRefError {
+    input: String,
+    reason: &'static str,
+}
+
+impl std::fmt::Display for ParseFlakeRefError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid flake reference '{}': {}",
+            self.input, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ParseFlakeRefError {}
+
+// Attributes which Nix writes as `0` or `1` in URL query parameters.
+static BOOL_ATTRS: &[&str] = &["allRefs", "exportIgnore", "lfs", "shallow", "submodules"];
+
+static ARCHIVE_EXTENSIONS: &[&str] = &[
+    ".zip", ".tar", ".tgz", ".tar.gz", ".tar.xz", ".tar.bz2", ".tar.zst",
+];
+
+fn is_archive_url(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    ARCHIVE_EXTENSIONS.iter().any(|ext| path.ends_with(ext))
+}
+
+fn is_rev(segment: &str) -> bool {
+    segment.len() == 40 && segment.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' | b':' | b'@' | b'!' | b'$' | b',' | b';' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl FlakeRef {
+    /// Query parameters for the URL form, sorted by key like Nix does.
+    /// Attributes that are part of the URL's path are left out.
+    fn query_params(&self) -> std::collections::BTreeMap<&str, String> {
+        let mut params = std::collections::BTreeMap::new();
+        let in_path = |key: &str| match self.r#type {
+            FlakeRefType::GitHub | FlakeRefType::GitLab | FlakeRefType::SourceHut => {
+                key == "rev" || (key == "ref" && self.rev.is_none())
+            }
+            FlakeRefType::Indirect => key == "rev" || key == "ref",
+            _ => false,
+        };
+        let strings = [
+            ("host", &self.host),
+            ("ref", &self.r#ref),
+            ("rev", &self.rev),
+            ("dir", &self.dir),
+            ("narHash", &self.nar_hash),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value.as_ref().filter(|_| !in_path(key)) {
+                params.insert(key, value.clone());
+            }
+        }
+        // Only `path:` references carry these as query parameters,
+        // every other fetcher recomputes them when fetching.
+        if self.r#type == FlakeRefType::Path {
+            let numbers = [
+                ("lastModified", self.last_modified),
+                ("revCount", self.rev_count),
+            ];
+            for (key, value) in numbers {
+                if let Some(value) = value {
+                    params.insert(key, value.to_string());
+                }
+            }
+        }
+        for (key, value) in &self.attrs {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Bool(value) => u8::from(*value).to_string(),
+                Value::Number(value) => value.to_string(),
+                _ => continue,
+            };
+            params.insert(key, value);
+        }
+        params
+    }
+
+    fn set_query_param(&mut self, key: &str, value: String) -> Result<(), &'static str> {
+        let number = || value.parse::<u64>().map_err(|_| "expected a number");
+        match key {
+            "host" => self.host = Some(value),
+            "ref" => self.r#ref = Some(value),
+            "rev" => self.rev = Some(value),
+            "dir" => self.dir = Some(value),
+            "narHash" => self.nar_hash = Some(value),
+            "lastModified" => self.last_modified = Some(number()?),
+            "revCount" => self.rev_count = Some(number()?),
+            "type" | "owner" | "repo" | "url" | "path" | "id" => {
+                return Err("query parameter conflicts with the URL");
+            }
+            key if BOOL_ATTRS.contains(&key) => {
+                let value = match value.as_str() {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err("expected a boolean"),
+                };
+                self.attrs.insert(key.to_owned(), Value::Bool(value));
+            }
+            key => {
+                self.attrs.insert(key.to_owned(), Value::String(value));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_url_path(r#type: FlakeRefType, rest: &str) -> Result<Self, &'static str> {
+        let mut flake_ref = Self::new(r#type);
+        match flake_ref.r#type {
+            FlakeRefType::GitHub | FlakeRefType::GitLab | FlakeRefType::SourceHut => {
+                let mut segments = rest.splitn(3, '/');
+                let (Some(owner), Some(repo)) = (segments.next(), segments.next()) else {
+                    return Err("expected 'owner/repo'");
+                };
+                if owner.is_empty() || repo.is_empty() {
+                    return Err("expected 'owner/repo'");
+                }
+                flake_ref.owner = Some(owner.to_owned());
+                flake_ref.repo = Some(repo.to_owned());
+                match segments.next() {
+                    Some(rev) if is_rev(rev) => flake_ref.rev = Some(rev.to_owned()),
+                    Some(r#ref) if !r#ref.is_empty() => flake_ref.r#ref = Some(r#ref.to_owned()),
+                    Some(_) => return Err("empty ref or revision"),
+                    None => {}
+                }
+            }
+            FlakeRefType::Indirect => {
+                let mut segments = rest.splitn(3, '/');
+                match segments.next() {
+                    Some(id) if !id.is_empty() => flake_ref.id = Some(id.to_owned()),
+                    _ => return Err("expected a flake identifier"),
+                }
+                for segment in segments {
+                    if is_rev(segment) {
+                        flake_ref.rev = Some(segment.to_owned());
+                    } else if flake_ref.r#ref.is_none() && !segment.is_empty() {
+                        flake_ref.r#ref = Some(segment.to_owned());
+                    } else {
+                        return Err("expected 'id/ref/rev'");
                     }
                 }
-                Ok(())
+            }
+            FlakeRefType::Path => {
+                if rest.is_empty() {
+                    return Err("expected a path");
+                }
+                flake_ref.path = Some(rest.to_owned());
+            }
+            FlakeRefType::Other(_) if rest.is_empty() => {}
+            _ => {
+                if !rest.contains(':') {
+                    return Err("expected a URL");
+                }
+                flake_ref.url = Some(rest.to_owned());
             }
         }
+        Ok(flake_ref)
     }
 }
 
-pub trait NodeEdgeRef<'a> {
-    fn index(self) -> Option<Ref<'a, str>>;
+/// Renders the canonical URL form of a flake reference,
+/// for example `github:NixOS/nixpkgs/nixos-unstable`
+/// or `git+https://example.org/repo.git?ref=main&rev=...`.
+impl std::fmt::Display for FlakeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let url = self.url.as_deref().unwrap_or_default();
+        match &self.r#type {
+            FlakeRefType::GitHub | FlakeRefType::GitLab | FlakeRefType::SourceHut => {
+                let owner = self.owner.as_deref().unwrap_or_default();
+                let repo = self.repo.as_deref().unwrap_or_default();
+                write!(f, "{}:{owner}/{repo}", self.r#type)?;
+                if let Some(rev_or_ref) = self.rev.as_ref().or(self.r#ref.as_ref()) {
+                    write!(f, "/{rev_or_ref}")?;
+                }
+            }
+            FlakeRefType::Indirect => {
+                write!(f, "flake:{}", self.id.as_deref().unwrap_or_default())?;
+                for segment in [&self.r#ref, &self.rev].into_iter().flatten() {
+                    write!(f, "/{segment}")?;
+                }
+            }
+            FlakeRefType::Path => write!(f, "path:{}", self.path.as_deref().unwrap_or_default())?,
+            FlakeRefType::Tarball if is_archive_url(url) && url.starts_with("http") => {
+                write!(f, "{url}")?
+            }
+            FlakeRefType::Other(_) if self.url.is_none() => write!(f, "{}:", self.r#type)?,
+            r#type => write!(f, "{type}+{url}")?,
+        }
+        let mut separator = '?';
+        for (key, value) in self.query_params() {
+            write!(f, "{separator}{key}={}", percent_encode(&value))?;
+            separator = '&';
+        }
+        Ok(())
+    }
+}
 
-    #[expect(unused)]
-    fn path(self) -> Option<Ref<'a, Vec<String>>>;
+/// Parses the URL form of a flake reference, accepting the same syntax
+/// that Nix accepts for the `url` attribute of a flake input.
+impl std::str::FromStr for FlakeRef {
+    type Err = ParseFlakeRefError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| ParseFlakeRefError {
+            input: s.to_owned(),
+            reason,
+        };
+        if s.contains('#') {
+            return Err(error("flake output attributes are not allowed here"));
+        }
+        let (base, query) = s.split_once('?').unwrap_or((s, ""));
+
+        let (r#type, rest) = match base.split_once(':') {
+            Some((scheme, rest)) if !scheme.contains('/') => match scheme.split_once('+') {
+                Some((r#type, _)) => (FlakeRefType::from(r#type), &base[r#type.len() + 1..]),
+                None => match scheme {
+                    "flake" => (FlakeRefType::Indirect, rest),
+                    "http" | "https" if is_archive_url(base) => (FlakeRefType::Tarball, base),
+                    "http" | "https" => (FlakeRefType::File, base),
+                    "git" | "ssh" => (FlakeRefType::Git, base),
+                    "tarball" | "file" | "hg" => return Err(error("expected '<type>+<url>'")),
+                    scheme => (FlakeRefType::from(scheme), rest),
+                },
+            },
+            _ if base.starts_with(['.', '/']) => (FlakeRefType::Path, base),
+            _ => (FlakeRefType::Indirect, base),
+        };
+        let mut flake_ref = FlakeRef::parse_url_path(r#type, rest).map_err(error)?;
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value).ok_or_else(|| error("bad percent-encoding"))?;
+            flake_ref.set_query_param(key, value).map_err(error)?;
+        }
+        Ok(flake_ref)
+    }
 }
 
-impl<'a> NodeEdgeRef<'a> for Ref<'a, NodeEdge> {
-    fn index(self) -> Option<Ref<'a, str>> {
-        Ref::filter_map(self, NodeEdge::index).ok()
+impl NodeEdge {
+    pub fn id(&self) -> Option<NodeId> {
+        match self {
+            Self::Indexed(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn path(&self) -> Option<&Vec<String>> {
+        match self {
+            Self::Follows(path) => Some(path),
+            _ => None,
+        }
     }
 
-    fn path(self) -> Option<Ref<'a, Vec<String>>> {
-        Ref::filter_map(self, NodeEdge::path).ok()
+    /// Display the edge as the node index that it references,
+    /// or the `follows` path separated by slashes.
+    pub fn display<'a>(&'a self, lock: &'a LockFile) -> impl std::fmt::Display + 'a {
+        DisplayNodeEdge { edge: self, lock }
     }
 }
 
-impl From<&str> for NodeEdge {
-    fn from(value: &str) -> Self {
-        Self::Indexed(value.to_string())
+struct DisplayNodeEdge<'a> {
+    edge: &'a NodeEdge,
+    lock: &'a LockFile,
+}
+
+impl std::fmt::Display for DisplayNodeEdge<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.edge {
+            NodeEdge::Indexed(id) => write!(f, "{}", self.lock.node_index(*id)),
+            NodeEdge::Follows(path) => write!(f, "{}", path.join("/")),
+        }
     }
 }
 
-impl From<String> for NodeEdge {
-    fn from(value: String) -> Self {
+impl From<NodeId> for NodeEdge {
+    fn from(value: NodeId) -> Self {
         Self::Indexed(value)
     }
 }
@@ -127,95 +638,843 @@ where
     }
 }
 
-impl Node {
-    fn edges(&self) -> &IndexMap<String, RefCell<NodeEdge>> {
+impl<E> LockedNode<E> {
+    pub fn is_flake(&self) -> bool {
+        self.flake
+    }
+
+    pub fn locked(&self) -> &FlakeRef {
+        &self.locked
+    }
+
+    pub fn original(&self) -> &FlakeRef {
+        &self.original
+    }
+}
+
+impl<E> Node<E> {
+    pub fn as_locked(&self) -> Option<&LockedNode<E>> {
+        match self {
+            Self::Locked(node) => Some(node),
+            Self::Unlocked(_) => None,
+        }
+    }
+
+    fn edges(&self) -> &IndexMap<String, E> {
         match self {
             Self::Locked(LockedNode { inputs, .. }) => inputs,
             Self::Unlocked(UnlockedNode { inputs }) => inputs,
         }
     }
 
-    pub fn iter_edges(&self) -> impl Iterator<Item = (&str, Ref<NodeEdge>)> {
-        self.edges()
-            .iter()
-            .map(|(name, edge)| (name.as_str(), edge.borrow()))
+    fn edges_mut(&mut self) -> &mut IndexMap<String, E> {
+        match self {
+            Self::Locked(LockedNode { inputs, .. }) => inputs,
+            Self::Unlocked(UnlockedNode { inputs }) => inputs,
+        }
     }
 
-    pub fn iter_edges_mut(&self) -> impl Iterator<Item = (&str, RefMut<NodeEdge>)> {
+    pub fn iter_edges(&self) -> impl Iterator<Item = (&str, &E)> {
         self.edges()
             .iter()
-            .map(|(name, edge)| (name.as_str(), edge.borrow_mut()))
+            .map(|(name, edge)| (name.as_str(), edge))
     }
 
-    pub fn get_edge(&self, name: impl AsRef<str>) -> Option<Ref<NodeEdge>> {
-        self.edges().get(name.as_ref()).map(|cell| cell.borrow())
+    pub fn iter_edges_mut(&mut self) -> impl Iterator<Item = (&str, &mut E)> {
+        self.edges_mut()
+            .iter_mut()
+            .map(|(name, edge)| (name.as_str(), edge))
     }
 
-    #[expect(unused)]
-    pub fn get_edge_mut(&self, name: impl AsRef<str>) -> Option<RefMut<NodeEdge>> {
-        self.edges()
-            .get(name.as_ref())
-            .map(|cell| cell.borrow_mut())
+    pub fn get_edge(&self, name: impl AsRef<str>) -> Option<&E> {
+        self.edges().get(name.as_ref())
+    }
+
+    pub fn get_edge_mut(&mut self, name: impl AsRef<str>) -> Option<&mut E> {
+        self.edges_mut().get_mut(name.as_ref())
+    }
+
+    /// Add or replace an input, keeping the inputs sorted by name as Nix writes them.
+    pub fn insert_edge(&mut self, name: impl Into<String>, edge: E) -> Option<E> {
+        let edges = self.edges_mut();
+        let old = edges.insert(name.into(), edge);
+        edges.sort_keys();
+        old
+    }
+
+    /// Remove an input, keeping the order of the others.
+    pub fn remove_edge(&mut self, name: impl AsRef<str>) -> Option<E> {
+        self.edges_mut().shift_remove(name.as_ref())
+    }
+
+    fn map_edges<F>(&self, mut op: impl FnMut(&E) -> F) -> Node<F> {
+        let inputs = self
+            .edges()
+            .iter()
+            .map(|(name, edge)| (name.clone(), op(edge)))
+            .collect();
+        match self {
+            Self::Locked(node) => Node::Locked(LockedNode {
+                flake: node.flake,
+                inputs,
+                locked: node.locked.clone(),
+                original: node.original.clone(),
+            }),
+            Self::Unlocked(_) => Node::Unlocked(UnlockedNode { inputs }),
+        }
     }
 }
 
 impl LockFile {
-    #[expect(unused)]
     pub fn new() -> Self {
         static ROOT: &str = "root";
         Self {
-            nodes: IndexMap::from_iter([(
-                ROOT.into(),
-                RefCell::new(Node::Unlocked(UnlockedNode {
+            slots: vec![Slot {
+                index: ROOT.into(),
+                node: Some(Node::Unlocked(UnlockedNode {
                     inputs: IndexMap::new(),
                 })),
-            )]),
-            root: ROOT.into(),
+            }],
+            ids: HashMap::from_iter([(ROOT.into(), NodeId(0))]),
+            root: NodeId(0),
             version: MAX_SUPPORTED_LOCK_VERSION,
         }
     }
 
-    pub fn root(&self) -> Option<Ref<Node>> {
-        self.nodes.get(&self.root).map(RefCell::borrow)
+    /// Deserialize a lock file, refusing schema versions which are not supported.
+    pub fn from_reader(reader: impl std::io::Read) -> Result<Self> {
+        let deserializer = &mut serde_json::Deserializer::from_reader(reader);
+        let lock: Self = serde_path_to_error::deserialize(deserializer)?;
+        lock.check_version()?;
+        Ok(lock)
+    }
+
+    /// Check that the schema version is between [`MIN_SUPPORTED_LOCK_VERSION`]
+    /// and [`MAX_SUPPORTED_LOCK_VERSION`].
+    pub fn check_version(&self) -> Result<()> {
+        if (MIN_SUPPORTED_LOCK_VERSION..=MAX_SUPPORTED_LOCK_VERSION).contains(&self.version) {
+            Ok(())
+        } else {
+            Err(Error::UnsupportedVersion {
+                version: self.version,
+            })
+        }
+    }
+
+    // Get the ID for a node index, reserving a vacant slot if it has not been seen.
+    fn intern(&mut self, index: &str) -> NodeId {
+        if let Some(&id) = self.ids.get(index) {
+            return id;
+        }
+        let id = NodeId(self.slots.len());
+        self.slots.push(Slot {
+            index: index.to_owned(),
+            node: None,
+        });
+        self.ids.insert(index.to_owned(), id);
+        id
+    }
+
+    fn from_raw(raw: RawLockFile) -> Self {
+        let mut lock = Self {
+            slots: Vec::with_capacity(raw.nodes.len()),
+            ids: HashMap::with_capacity(raw.nodes.len()),
+            root: NodeId(0),
+            version: raw.version,
+        };
+        for index in raw.nodes.keys() {
+            lock.intern(index);
+        }
+        lock.root = lock.intern(&raw.root);
+        for (index, node) in &raw.nodes {
+            let node = node.map_edges(|edge| match edge {
+                RawNodeEdge::Indexed(target) => NodeEdge::Indexed(lock.intern(target)),
+                RawNodeEdge::Follows(path) => NodeEdge::Follows(path.clone()),
+            });
+            let id = lock.ids[index];
+            lock.slots[id.0].node = Some(node);
+        }
+        lock
+    }
+
+    fn to_raw(&self) -> RawLockFile {
+        let nodes = self
+            .node_ids()
+            .map(|id| (self.node_index(id).to_owned(), self.raw_node(id).unwrap()))
+            .collect();
+        RawLockFile {
+            nodes,
+            root: self.root_index().to_owned(),
+            version: self.version,
+        }
+    }
+
+    /// The node as it is written in the lock file, with inputs referencing nodes by index.
+    pub fn raw_node(&self, id: NodeId) -> Option<Node<RawNodeEdge>> {
+        let node = self.get_node(id)?;
+        Some(node.map_edges(|edge| match edge {
+            NodeEdge::Indexed(target) => RawNodeEdge::Indexed(self.node_index(*target).to_owned()),
+            NodeEdge::Follows(path) => RawNodeEdge::Follows(path.clone()),
+        }))
+    }
+
+    /// Insert nodes as they are written in the lock file, each by its index at
+    /// its position in the order of the lock file, in ascending order of position.
+    /// A node which already exists by the same index is moved and replaced.
+    ///
+    /// The lock is rebuilt, so the IDs of its nodes are no longer valid.
+    pub fn insert_raw_nodes(
+        &mut self,
+        nodes: impl IntoIterator<Item = (usize, String, Node<RawNodeEdge>)>,
+    ) {
+        let mut raw = self.to_raw();
+        for (position, index, node) in nodes {
+            raw.nodes.shift_remove(&index);
+            let position = position.min(raw.nodes.len());
+            raw.nodes.shift_insert(position, index, node);
+        }
+        *self = Self::from_raw(raw);
+    }
+
+    pub fn root(&self) -> Option<&Node> {
+        self.get_node(self.root)
+    }
+
+    pub fn root_id(&self) -> NodeId {
+        self.root
     }
 
     pub fn root_index(&self) -> &str {
-        &self.root
+        self.node_index(self.root)
     }
 
     pub fn version(&self) -> u32 {
         self.version
     }
 
+    /// IDs of the nodes that exist, in the order of the lock file.
+    pub fn node_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.node.is_some())
+            .map(|(id, _)| NodeId(id))
+    }
+
+    /// Indices of the nodes that exist, in the order of the lock file.
     pub fn node_indices(&self) -> impl Iterator<Item = &str> {
-        self.nodes.keys().map(String::as_str)
+        self.node_ids().map(|id| self.node_index(id))
+    }
+
+    /// The index of a node, as used for the keys of `nodes` in the lock file.
+    /// This is also known for IDs of nodes which do not exist.
+    pub fn node_index(&self, id: NodeId) -> &str {
+        &self.slots[id.0].index
+    }
+
+    /// The ID of the node at the index, if that node exists.
+    pub fn find_node(&self, index: impl AsRef<str>) -> Option<NodeId> {
+        let id = *self.ids.get(index.as_ref())?;
+        self.get_node(id).map(|_| id)
+    }
+
+    pub fn get_node(&self, id: NodeId) -> Option<&Node> {
+        self.slots.get(id.0)?.node.as_ref()
+    }
+
+    pub fn get_node_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        self.slots.get_mut(id.0)?.node.as_mut()
+    }
+
+    pub fn remove_node(&mut self, id: NodeId) -> Option<Node> {
+        self.slots.get_mut(id.0)?.node.take()
     }
 
-    pub fn get_node(&self, index: impl AsRef<str>) -> Option<Ref<Node>> {
-        self.nodes.get(index.as_ref()).map(RefCell::borrow)
+    /// The node that the edge leads to, which does not necessarily exist
+    /// if the edge is indexed.
+    pub fn resolve_edge(&self, edge: &NodeEdge) -> Option<NodeId> {
+        self.resolve_edge_guarded(edge, &mut Vec::new())
     }
 
-    #[expect(unused)]
-    pub fn get_node_mut(&self, index: impl AsRef<str>) -> Option<RefMut<Node>> {
-        self.nodes.get(index.as_ref()).map(RefCell::borrow_mut)
+    pub fn follow_path(&self, path: impl IntoIterator<Item = impl AsRef<str>>) -> Option<NodeId> {
+        self.resolve_edge(&NodeEdge::from_iter(path))
     }
 
-    pub fn remove_node(&mut self, index: impl AsRef<str>) -> Option<Node> {
-        self.nodes
-            .shift_remove(index.as_ref())
-            .map(|cell| cell.into_inner())
+    /// Resolve the `follows` path like [`LockFile::follow_path`], pushing every input
+    /// it passes through to `inputs`, including those of the `follows` paths along it.
+    /// An input which is passed through twice is a cycle, which does not resolve.
+    pub fn follow_path_inputs(
+        &self,
+        path: &[String],
+        inputs: &mut Vec<(NodeId, String)>,
+    ) -> Option<NodeId> {
+        let mut id = self.root;
+        for name in path {
+            let input = (id, name.clone());
+            if inputs.contains(&input) {
+                return None;
+            }
+            inputs.push(input);
+            id = match self.get_node(id)?.get_edge(name)? {
+                NodeEdge::Indexed(target) => *target,
+                NodeEdge::Follows(path) => self.follow_path_inputs(path, inputs)?,
+            };
+        }
+        Some(id)
     }
 
-    pub fn resolve_edge(&self, edge: &NodeEdge) -> Option<String> {
+    // A `follows` path which needs itself to be resolved first would recurse forever,
+    // so the paths currently being resolved are kept in `resolving`.
+    fn resolve_edge_guarded(
+        &self,
+        edge: &NodeEdge,
+        resolving: &mut Vec<Vec<String>>,
+    ) -> Option<NodeId> {
         match edge {
-            NodeEdge::Indexed(index) => Some(index.to_owned()),
-            NodeEdge::Follows(path) => self.follow_path(path),
+            NodeEdge::Indexed(id) => Some(*id),
+            NodeEdge::Follows(path) => {
+                if resolving.contains(path) {
+                    return None;
+                }
+                resolving.push(path.clone());
+                let id = path.iter().try_fold(self.root, |id, name| {
+                    self.resolve_edge_guarded(self.get_node(id)?.get_edge(name)?, resolving)
+                });
+                resolving.pop();
+                id
+            }
         }
     }
 
-    pub fn follow_path(&self, path: impl IntoIterator<Item = impl AsRef<str>>) -> Option<String> {
-        path.into_iter().try_fold(self.root.clone(), |index, name| {
-            self.resolve_edge(&*self.get_node(index)?.get_edge(name)?)
-        })
+    /// IDs of the existing nodes that the edges of a node resolve to.
+    pub fn edge_targets(&self, id: NodeId) -> Vec<NodeId> {
+        let Some(node) = self.get_node(id) else {
+            return Vec::new();
+        };
+        node.iter_edges()
+            .filter_map(|(_, edge)| self.resolve_edge(edge))
+            .filter(|&target| self.get_node(target).is_some())
+            .collect()
+    }
+
+    /// Find the cycles in the node graph reachable from the root.
+    /// Each cycle is the chain of nodes starting from the first node
+    /// of the cycle that was reached, through its inputs, back to that same node.
+    pub fn find_cycles(&self) -> Vec<Vec<NodeId>> {
+        fn visit(
+            lock: &LockFile,
+            id: NodeId,
+            stack: &mut Vec<NodeId>,
+            done: &mut HashSet<NodeId>,
+            cycles: &mut Vec<Vec<NodeId>>,
+        ) {
+            stack.push(id);
+            for target in lock.edge_targets(id) {
+                if let Some(start) = stack.iter().position(|&id| id == target) {
+                    let mut chain = stack[start..].to_vec();
+                    chain.push(target);
+                    cycles.push(chain);
+                } else if !done.contains(&target) {
+                    visit(lock, target, stack, done, cycles);
+                }
+            }
+            stack.pop();
+            done.insert(id);
+        }
+
+        let mut cycles = Vec::new();
+        if self.root().is_some() {
+            visit(
+                self,
+                self.root,
+                &mut Vec::new(),
+                &mut HashSet::new(),
+                &mut cycles,
+            );
+        }
+        cycles
+    }
+
+    /// The shortest input path from the root to every reachable node,
+    /// in breadth-first order. Edges which do not resolve are skipped.
+    pub fn input_paths(&self) -> IndexMap<NodeId, Vec<String>> {
+        self.input_paths_through(|_| true)
+    }
+
+    /// The shortest input path from the root to every reachable node which passes
+    /// through no `follows` edges, in breadth-first order. Every node which is
+    /// reachable at all has such a path, and unlike those of [`LockFile::input_paths`],
+    /// it still leads to the same node when `follows` edges are rewritten.
+    pub fn indexed_input_paths(&self) -> IndexMap<NodeId, Vec<String>> {
+        self.input_paths_through(|edge| edge.path().is_none())
+    }
+
+    fn input_paths_through(
+        &self,
+        walk: impl Fn(&NodeEdge) -> bool,
+    ) -> IndexMap<NodeId, Vec<String>> {
+        let mut paths = IndexMap::new();
+        if self.root().is_none() {
+            return paths;
+        }
+        paths.insert(self.root, Vec::new());
+        let mut cursor = 0;
+        while let Some((&id, path)) = paths.get_index(cursor) {
+            let path = path.clone();
+            for (name, edge) in self.get_node(id).unwrap().iter_edges() {
+                let Some(target) = self.resolve_edge(edge).filter(|_| walk(edge)) else {
+                    continue;
+                };
+                if self.get_node(target).is_some() && !paths.contains_key(&target) {
+                    let mut path = path.clone();
+                    path.push(name.to_owned());
+                    paths.insert(target, path);
+                }
+            }
+            cursor += 1;
+        }
+        paths
+    }
+
+    /// Check the integrity of the node graph, reporting every broken edge,
+    /// a missing root, and nodes that are not reachable from the root.
+    pub fn validate(&self) -> Vec<LockIssue> {
+        let mut issues = Vec::new();
+        if self.root().is_none() {
+            issues.push(LockIssue::MissingRoot {
+                root: self.root_index().to_owned(),
+            });
+        }
+        let paths = self.input_paths();
+        for id in self.node_ids() {
+            let index = self.node_index(id);
+            let input_path = |name: &str| {
+                paths.get(&id).map(|path| {
+                    let mut path = path.clone();
+                    path.push(name.to_owned());
+                    path
+                })
+            };
+            for (name, edge) in self.get_node(id).unwrap().iter_edges() {
+                let issue = match edge {
+                    NodeEdge::Indexed(target) if self.get_node(*target).is_none() => {
+                        LockIssue::DanglingEdge {
+                            node: index.to_owned(),
+                            input: name.to_owned(),
+                            input_path: input_path(name),
+                            target: self.node_index(*target).to_owned(),
+                        }
+                    }
+                    NodeEdge::Follows(follows)
+                        if self
+                            .follow_path(follows)
+                            .is_none_or(|target| self.get_node(target).is_none()) =>
+                    {
+                        LockIssue::UnresolvedFollows {
+                            node: index.to_owned(),
+                            input: name.to_owned(),
+                            input_path: input_path(name),
+                            follows: follows.clone(),
+                        }
+                    }
+                    _ => continue,
+                };
+                issues.push(issue);
+            }
+        }
+        for chain in self.find_cycles() {
+            let chain = chain
+                .into_iter()
+                .map(|id| self.node_index(id).to_owned())
+                .collect();
+            issues.push(LockIssue::Cycle { chain });
+        }
+        for id in self.node_ids() {
+            if !paths.contains_key(&id) && id != self.root {
+                issues.push(LockIssue::Orphan {
+                    node: self.node_index(id).to_owned(),
+                });
+            }
+        }
+        issues
+    }
+}
+
+impl Default for LockFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Serialize for LockFile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_raw().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for LockFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RawLockFile::deserialize(deserializer).map(Self::from_raw)
+    }
+}
+
+/// A problem with the node graph of a lock file, found by [`LockFile::validate`].
+///
+/// The `input_path` of an edge is the shortest path of input names from the root,
+/// which is absent when the node that owns the edge is itself unreachable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LockIssue {
+    /// The node named by the top-level `root` attribute does not exist.
+    MissingRoot { root: String },
+    /// An edge references a node index that does not exist.
+    DanglingEdge {
+        node: String,
+        input: String,
+        input_path: Option<Vec<String>>,
+        target: String,
+    },
+    /// A `follows` edge whose path does not lead to an existing node.
+    UnresolvedFollows {
+        node: String,
+        input: String,
+        input_path: Option<Vec<String>>,
+        follows: Vec<String>,
+    },
+    /// A `follows` edge whose path resolves to another node after pruning,
+    /// or after the paths were rewritten, see [`crate::prune::normalize_follows_paths`].
+    RetargetedFollows {
+        node: String,
+        input: String,
+        input_path: Option<Vec<String>>,
+        follows: Vec<String>,
+        expected: String,
+        found: String,
+    },
+    /// A chain of node indices where the last input leads back to the first node.
+    Cycle { chain: Vec<String> },
+    /// A node which can not be reached from the root.
+    Orphan { node: String },
+}
+
+impl LockIssue {
+    /// Orphaned nodes are harmless to every command, and are removed by pruning.
+    /// Cycles are skipped over when traversing the graph.
+    /// Every other issue makes the graph impossible to traverse.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, Self::Cycle { .. } | Self::Orphan { .. })
+    }
+}
+
+impl std::fmt::Display for LockIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let describe_edge =
+            |f: &mut std::fmt::Formatter<'_>, node: &str, input: &str, path: &Option<Vec<_>>| {
+                write!(f, "input '{input}' of node '{node}'")?;
+                match path {
+                    Some(path) => write!(f, " (at '{}')", path.join("/")),
+                    None => write!(f, " (unreachable)"),
+                }
+            };
+        match self {
+            Self::MissingRoot { root } => write!(f, "the root node '{root}' does not exist"),
+            Self::DanglingEdge {
+                node,
+                input,
+                input_path,
+                target,
+            } => {
+                describe_edge(f, node, input, input_path)?;
+                write!(f, " references missing node '{target}'")
+            }
+            Self::UnresolvedFollows {
+                node,
+                input,
+                input_path,
+                follows,
+            } => {
+                describe_edge(f, node, input, input_path)?;
+                write!(f, " follows '{}' which does not resolve", follows.join("/"))
+            }
+            Self::RetargetedFollows {
+                node,
+                input,
+                input_path,
+                follows,
+                expected,
+                found,
+            } => {
+                describe_edge(f, node, input, input_path)?;
+                write!(
+                    f,
+                    " follows '{}' which resolves to node '{found}' instead of '{expected}'",
+                    follows.join("/")
+                )
+            }
+            Self::Cycle { chain } => write!(f, "the inputs form a cycle: {}", chain.join(" -> ")),
+            Self::Orphan { node } => write!(f, "node '{node}' is not reachable from the root"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE_LOCKS: &[&str] = &[
+        "samples/hyprland/no-follows/flake.lock",
+        "samples/hyprland/with-follows/flake.lock",
+        "samples/nixpkgs-follows-nixpkgs-unstable/flake.lock",
+    ];
+
+    #[test]
+    fn sample_locks_round_trip() {
+        for path in SAMPLE_LOCKS {
+            let text = std::fs::read_to_string(path).unwrap();
+            let lock: LockFile = serde_json::from_str(&text).unwrap();
+            assert_eq!(
+                serde_json::to_value(&lock).unwrap(),
+                serde_json::from_str::<Value>(&text).unwrap(),
+                "{path} did not round-trip"
+            );
+        }
+    }
+
+    #[test]
+    fn flake_ref_fields() {
+        let flake_ref: FlakeRef = serde_json::from_value(serde_json::json!({
+            "lastModified": 1751569683,
+            "narHash": "sha256-PoQcCYTiN52PanxgWBN4Tqet1x4PCk6KtjaHNjELH88=",
+            "owner": "hyprwm",
+            "repo": "aquamarine",
+            "rev": "c0c56dde3e471030edb135425a82107cf0057c6f",
+            "type": "github"
+        }))
+        .unwrap();
+        assert_eq!(flake_ref.r#type, FlakeRefType::GitHub);
+        assert_eq!(flake_ref.owner.as_deref(), Some("hyprwm"));
+        assert_eq!(flake_ref.repo.as_deref(), Some("aquamarine"));
+        assert_eq!(flake_ref.last_modified, Some(1751569683));
+        assert!(flake_ref.attrs.is_empty());
+    }
+
+    #[test]
+    fn flake_ref_unknown_round_trip() {
+        let value = serde_json::json!({
+            "lastModified": "not a number",
+            "submodules": true,
+            "type": "s3",
+            "url": "s3://bucket/flake.tar.gz"
+        });
+        let flake_ref: FlakeRef = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(flake_ref.r#type, FlakeRefType::Other("s3".into()));
+        assert_eq!(flake_ref.last_modified, None);
+        assert_eq!(serde_json::to_value(&flake_ref).unwrap(), value);
+    }
+
+    #[test]
+    fn flake_ref_url_format() {
+        let cases = [
+            (
+                serde_json::json!({"owner": "NixOS", "ref": "nixos-unstable", "repo": "nixpkgs", "type": "github"}),
+                "github:NixOS/nixpkgs/nixos-unstable",
+            ),
+            (
+                serde_json::json!({"dir": "contrib", "host": "git.example.org", "owner": "me", "repo": "flake", "type": "gitlab"}),
+                "gitlab:me/flake?dir=contrib&host=git.example.org",
+            ),
+            (
+                serde_json::json!({"ref": "main", "rev": "c0c56dde3e471030edb135425a82107cf0057c6f", "type": "git", "url": "https://example.org/repo.git"}),
+                "git+https://example.org/repo.git?ref=main&rev=c0c56dde3e471030edb135425a82107cf0057c6f",
+            ),
+            (
+                serde_json::json!({"id": "nixpkgs", "ref": "nixos-24.05", "type": "indirect"}),
+                "flake:nixpkgs/nixos-24.05",
+            ),
+            (
+                serde_json::json!({"lastModified": 1700000000, "narHash": "sha256-ab+c/d=", "path": "/etc/nixos", "type": "path"}),
+                "path:/etc/nixos?lastModified=1700000000&narHash=sha256-ab%2Bc/d%3D",
+            ),
+            (
+                serde_json::json!({"type": "tarball", "url": "https://example.org/source.tar.gz"}),
+                "https://example.org/source.tar.gz",
+            ),
+            (
+                serde_json::json!({"type": "file", "url": "https://example.org/default.nix"}),
+                "file+https://example.org/default.nix",
+            ),
+            (
+                serde_json::json!({"submodules": true, "type": "git", "url": "file:///src"}),
+                "git+file:///src?submodules=1",
+            ),
+        ];
+        for (value, url) in cases {
+            let flake_ref: FlakeRef = serde_json::from_value(value).unwrap();
+            assert_eq!(flake_ref.to_string(), url);
+            assert_eq!(url.parse::<FlakeRef>().unwrap(), flake_ref, "{url}");
+        }
+    }
+
+    #[test]
+    fn flake_ref_url_parse() {
+        let flake_ref: FlakeRef = "nixpkgs/nixos-unstable".parse().unwrap();
+        assert_eq!(flake_ref.r#type, FlakeRefType::Indirect);
+        assert_eq!(flake_ref.id.as_deref(), Some("nixpkgs"));
+        assert_eq!(flake_ref.r#ref.as_deref(), Some("nixos-unstable"));
+
+        let flake_ref: FlakeRef = "github:hyprwm/Hyprland/c0c56dde3e471030edb135425a82107cf0057c6f"
+            .parse()
+            .unwrap();
+        assert_eq!(flake_ref.r#ref, None);
+        assert!(flake_ref.rev.is_some());
+
+        let flake_ref: FlakeRef = "./sub/flake".parse().unwrap();
+        assert_eq!(flake_ref.r#type, FlakeRefType::Path);
+
+        assert!("github:NixOS".parse::<FlakeRef>().is_err());
+        assert!("github:NixOS/nixpkgs#hello".parse::<FlakeRef>().is_err());
+        assert!("path:.?revCount=many".parse::<FlakeRef>().is_err());
+    }
+
+    #[test]
+    fn flake_ref_same_source() {
+        let same_source = |a: &str, b: &str| {
+            let a: FlakeRef = a.parse().unwrap();
+            a.same_source(&b.parse().unwrap())
+        };
+        assert!(same_source(
+            "github:NixOS/nixpkgs/nixos-unstable",
+            "github:nixos/nixpkgs/nixos-24.05"
+        ));
+        assert!(!same_source(
+            "github:NixOS/nixpkgs",
+            "github:NixOS/nixpkgs?dir=lib"
+        ));
+        assert!(!same_source(
+            "github:NixOS/nixpkgs",
+            "git+https://github.com/NixOS/nixpkgs"
+        ));
+        assert!(same_source("nixpkgs/nixos-unstable", "flake:nixpkgs"));
+        assert!(!same_source(
+            "git+https://example.com/a",
+            "git+https://example.com/b"
+        ));
+        assert!(!FlakeRef::new(FlakeRefType::Git).same_source(&FlakeRef::new(FlakeRefType::Git)));
+    }
+
+    #[test]
+    fn sample_flake_refs_parse_from_url() {
+        for path in SAMPLE_LOCKS {
+            let lock: LockFile =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            for id in lock.node_ids() {
+                let node = lock.get_node(id).unwrap();
+                let Some(node) = node.as_locked() else {
+                    continue;
+                };
+                let original = node.original();
+                assert_eq!(&original.to_string().parse::<FlakeRef>().unwrap(), original);
+                let mut locked = node.locked().clone();
+                locked.last_modified = None;
+                assert_eq!(locked.to_string().parse::<FlakeRef>().unwrap(), locked);
+            }
+        }
+    }
+
+    #[test]
+    fn validate_reports_broken_edges() {
+        let lock: LockFile = serde_json::from_value(serde_json::json!({
+            "nodes": {
+                "a": {
+                    "inputs": {
+                        "nixpkgs": "nixpkgs_9",
+                        "utils": ["missing", "utils"]
+                    },
+                    "locked": {"owner": "o", "repo": "a", "type": "github"},
+                    "original": {"owner": "o", "repo": "a", "type": "github"}
+                },
+                "lost": {
+                    "locked": {"owner": "o", "repo": "lost", "type": "github"},
+                    "original": {"owner": "o", "repo": "lost", "type": "github"}
+                },
+                "root": {"inputs": {"a": "a"}}
+            },
+            "root": "root",
+            "version": 7
+        }))
+        .unwrap();
+        let issues = lock.validate();
+        assert_eq!(
+            issues,
+            [
+                LockIssue::DanglingEdge {
+                    node: "a".into(),
+                    input: "nixpkgs".into(),
+                    input_path: Some(vec!["a".into(), "nixpkgs".into()]),
+                    target: "nixpkgs_9".into(),
+                },
+                LockIssue::UnresolvedFollows {
+                    node: "a".into(),
+                    input: "utils".into(),
+                    input_path: Some(vec!["a".into(), "utils".into()]),
+                    follows: vec!["missing".into(), "utils".into()],
+                },
+                LockIssue::Orphan {
+                    node: "lost".into()
+                },
+            ]
+        );
+        assert_eq!(issues.iter().filter(|issue| issue.is_fatal()).count(), 2);
+    }
+
+    #[test]
+    fn validate_sample_locks() {
+        for path in SAMPLE_LOCKS {
+            let lock: LockFile =
+                serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+            assert_eq!(lock.validate(), [], "{path} has issues");
+        }
+    }
+
+    #[test]
+    fn lock_file_is_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LockFile>();
+    }
+
+    #[test]
+    fn removed_nodes_are_not_serialized() {
+        let text = std::fs::read_to_string(SAMPLE_LOCKS[2]).unwrap();
+        let mut lock: LockFile = serde_json::from_str(&text).unwrap();
+        let id = lock.find_node("nixpkgs-release").unwrap();
+        lock.remove_node(id);
+        assert_eq!(lock.find_node("nixpkgs-release"), None);
+        let value = serde_json::to_value(&lock).unwrap();
+        assert!(value["nodes"].get("nixpkgs-release").is_none());
+        // The root edge still serializes by the index of the missing node.
+        assert_eq!(
+            value["nodes"]["root"]["inputs"]["nixpkgs-release"],
+            "nixpkgs-release"
+        );
+    }
+
+    #[test]
+    fn read_lock_errors() {
+        let error = LockFile::from_reader(&b"{ \"nodes\": {"[..]).unwrap_err();
+        assert_eq!(error.exit_code(), Error::EXIT_PARSE);
+
+        let error = LockFile::from_reader(
+            &br#"{"nodes": {"root": {"inputs": {}}}, "root": "root", "version": 4}"#[..],
+        )
+        .unwrap_err();
+        assert_eq!(error.exit_code(), Error::EXIT_UNSUPPORTED_VERSION);
+
+        let error = LockFile::from_reader(
+            &br#"{"nodes": {"root": {"inputs": {}}}, "root": "root", "version": 8}"#[..],
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::UnsupportedVersion { version: 8 }));
+
+        let lock = std::fs::File::open(SAMPLE_LOCKS[0]).unwrap();
+        assert!(LockFile::from_reader(lock).is_ok());
     }
 }
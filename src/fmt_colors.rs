@@ -82,7 +82,6 @@ macro_rules! format_args_colored {
 
 #[cfg(test)]
 mod tests {
-    use crate::elogln;
     use owo_colors::OwoColorize;
 
     struct NoCopy(Vec<String>);
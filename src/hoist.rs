@@ -0,0 +1,211 @@
+//! Promoting transitive inputs which several root inputs share
+//! to inputs of the root, so that pruning can redirect them all to one node.
+
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
+
+use crate::flake_lock::{LockFile, NodeEdge, NodeId};
+use crate::prune::SubstituteOptions;
+use crate::EXPECT_ROOT_EXIST;
+
+/// A transitive input which was promoted to an input of the root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hoist {
+    /// The name of the new root input.
+    pub name: String,
+    /// The node which the new root input references.
+    pub node: NodeId,
+    /// The input paths which referenced a node of the same source by that name,
+    /// and which substitution will redirect to the new root input.
+    pub paths: Vec<Vec<String>>,
+}
+
+impl Hoist {
+    /// The lines to add to the `inputs` of the `flake.nix`, so that the root input
+    /// exists after the lock is updated. They are derived from the `original`
+    /// reference of the node, such as `inputs.hyprutils.url = "github:hyprwm/hyprutils";`.
+    pub fn flake_nix_declaration(&self, lock: &LockFile) -> String {
+        let Some(node) = lock.get_node(self.node).and_then(|node| node.as_locked()) else {
+            return String::new();
+        };
+        let mut declaration = format!("inputs.{}.url = \"{}\";", self.name, node.original());
+        if !node.is_flake() {
+            declaration.push_str(&format!("\ninputs.{}.flake = false;", self.name));
+        }
+        declaration
+    }
+}
+
+/// Add an input to the root for every name by which the inputs of at least
+/// `min_root_inputs` different root inputs reference nodes of the same source,
+/// when the root has no input by that name yet. Substitution then redirects
+/// all of those inputs to the new root input, as it would for any other.
+///
+/// Only the inputs which substitution would visit are counted, that is those of
+/// nodes within [`SubstituteOptions::depth`] which the rules allow to be redirected,
+/// and their names are what [`crate::rules::FollowRules::target`] makes of them.
+/// Names which reference nodes of different sources are left alone.
+/// A `min_root_inputs` below 2 is taken as 2, as an input which only
+/// one root input references has nothing to be shared with.
+///
+/// The node which is promoted is the one referenced by the most of those inputs,
+/// ties going to the newest revision and then to the first node in the lock.
+pub fn hoist_shared_inputs(
+    lock: &mut LockFile,
+    options: &SubstituteOptions,
+    min_root_inputs: usize,
+) -> Vec<Hoist> {
+    let root = lock.root().expect(EXPECT_ROOT_EXIST);
+    let within_depth = |depth: usize| options.depth.is_none_or(|max| depth <= max);
+
+    let mut candidates = IndexMap::<String, Vec<(NodeId, Vec<String>)>>::new();
+    for (id, path) in lock.input_paths() {
+        if id == lock.root_id() || !within_depth(path.len()) {
+            continue;
+        }
+        for (input, edge) in lock.get_node(id).unwrap().iter_edges() {
+            let input_path = [path.as_slice(), &[input.to_owned()]].concat();
+            let name = options.rules.target(&input_path);
+            if root.get_edge(name).is_some() || options.rules.check(lock, id, &input_path).is_err()
+            {
+                continue;
+            }
+            let Some(target) = lock.resolve_edge(edge) else {
+                continue;
+            };
+            candidates
+                .entry(name.to_owned())
+                .or_default()
+                .push((target, input_path));
+        }
+    }
+
+    let mut hoists = Vec::new();
+    for (name, references) in candidates {
+        let root_inputs = references
+            .iter()
+            .map(|(_, path)| &path[0])
+            .collect::<HashSet<_>>();
+        if root_inputs.len() < min_root_inputs.max(2) {
+            continue;
+        }
+        let Some(node) = pick_node(lock, &references) else {
+            continue;
+        };
+        hoists.push(Hoist {
+            name,
+            node,
+            paths: references.into_iter().map(|(_, path)| path).collect(),
+        });
+    }
+
+    let root = lock.get_node_mut(lock.root_id()).expect(EXPECT_ROOT_EXIST);
+    for hoist in &hoists {
+        root.insert_edge(hoist.name.clone(), NodeEdge::Indexed(hoist.node));
+    }
+    hoists
+}
+
+// The most referenced node, or `None` if the nodes are not all of the same source.
+fn pick_node(lock: &LockFile, references: &[(NodeId, Vec<String>)]) -> Option<NodeId> {
+    let mut counts = IndexMap::<NodeId, usize>::new();
+    for (id, _) in references {
+        *counts.entry(*id).or_default() += 1;
+    }
+    let nodes = counts
+        .into_iter()
+        .map(|(id, count)| Some((lock.get_node(id)?.as_locked()?, id, count)))
+        .collect::<Option<Vec<_>>>()?;
+    let (first, ..) = nodes.first()?;
+    if !nodes
+        .iter()
+        .all(|(node, ..)| node.original().same_source(first.original()))
+    {
+        return None;
+    }
+    nodes
+        .iter()
+        .rev()
+        .max_by_key(|(node, _, count)| {
+            let locked = node.locked();
+            (*count, locked.last_modified, locked.rev_count)
+        })
+        .map(|(_, id, _)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::plan::Plan;
+    use crate::prune::{prune_orphan_nodes, substitute_flake_inputs_with_follows};
+    use crate::testing::*;
+
+    #[test]
+    fn hoist_shared_inputs_flake_lock() {
+        let node = |repo: &str, rev: &str, last_modified: u64| {
+            let mut node = github_node(repo, json!({}));
+            node["locked"]["rev"] = rev.into();
+            node["locked"]["lastModified"] = last_modified.into();
+            node
+        };
+        let with_inputs = |repo: &str, inputs: serde_json::Value| {
+            let mut node = node(repo, "x", 1);
+            node["inputs"] = inputs;
+            node
+        };
+        let original = lock_of(json!({
+            "a": with_inputs("a", json!({"utils": "utils", "lib": "lib"})),
+            "b": with_inputs("b", json!({"utils": "utils_2", "lib": "lib_2"})),
+            "c": with_inputs("c", json!({"utils": "utils_3"})),
+            "lib": node("lib", "x", 1),
+            "lib_2": node("other-lib", "x", 1),
+            "utils": node("utils", "old", 1),
+            "utils_2": node("utils", "new", 2),
+            "utils_3": node("utils", "new", 2),
+            "root": {"inputs": {"a": "a", "b": "b", "c": "c"}}
+        }));
+
+        let mut lock = original.clone();
+        let options = SubstituteOptions::default();
+        assert!(hoist_shared_inputs(&mut lock, &options, 4).is_empty());
+        // The `lib` inputs of `a` and `b` are of different sources.
+        assert_eq!(hoist_shared_inputs(&mut lock.clone(), &options, 2).len(), 1);
+        // Less than 2 is taken as 2, so the `lib` input of `a` alone is not hoisted.
+        assert_eq!(hoist_shared_inputs(&mut lock.clone(), &options, 0).len(), 1);
+        let hoists = hoist_shared_inputs(&mut lock, &options, 3);
+        let [hoist] = hoists.as_slice() else {
+            panic!("expected one hoist, got {hoists:?}");
+        };
+        assert_eq!(hoist.name, "utils");
+        assert_eq!(lock.node_index(hoist.node), "utils_2");
+        assert_eq!(hoist.paths.len(), 3);
+        assert_eq!(
+            hoist.flake_nix_declaration(&lock),
+            "inputs.utils.url = \"github:o/utils\";"
+        );
+        let root_inputs = lock
+            .root()
+            .unwrap()
+            .iter_edges()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(root_inputs, ["a", "b", "c", "utils"]);
+
+        let substitutions = substitute_flake_inputs_with_follows(&mut lock, &options);
+        let removed = prune_orphan_nodes(&mut lock);
+        let remaining = lock.node_indices().collect::<Vec<_>>();
+        assert_eq!(
+            remaining,
+            ["a", "b", "c", "lib", "lib_2", "root", "utils_2"]
+        );
+
+        let plan = Plan::new(&original, &lock, &substitutions, &removed);
+        assert_eq!(plan.hoisted.len(), 1);
+        let mut applied = original.clone();
+        plan.apply(&mut applied).unwrap();
+        assert_eq!(applied, lock);
+    }
+}
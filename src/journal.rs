@@ -0,0 +1,321 @@
+//! A record of everything pruning changed in a lock, including the nodes it
+//! removed in full, so that the lock from before pruning can be restored.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::check::{diff_locks, Change};
+use crate::error::{Error, Result};
+use crate::flake_lock::{LockFile, Node, NodeEdge, RawNodeEdge};
+use crate::plan::PlannedEdge;
+
+/// Every input which was redirected and every node which was removed
+/// between two locks, see [`Journal::new`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Journal {
+    pub edges: Vec<JournalEdge>,
+    pub removed: Vec<JournalNode>,
+}
+
+/// An input which references something else after pruning,
+/// with nodes referenced by their index.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournalEdge {
+    /// The input path from the root to the input before pruning,
+    /// if the node was reachable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<Vec<String>>,
+    /// The index of the node which owns the input.
+    pub node: String,
+    pub input: String,
+    /// The edge before pruning, or `None` if the input was added, such as by hoisting.
+    pub old: Option<PlannedEdge>,
+    /// The edge after pruning, or `None` if the input was removed.
+    pub new: Option<PlannedEdge>,
+}
+
+/// A node which pruning removed, as it was written in the lock file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournalNode {
+    pub index: String,
+    /// The position of the node in the order of the lock file before pruning.
+    pub position: usize,
+    pub node: Node<RawNodeEdge>,
+}
+
+/// Why a journal could not be read or restored to a lock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalError {
+    /// The journal is not valid JSON, or does not have the shape of a journal.
+    Invalid(String),
+    /// The journal references a node which the lock does not have.
+    MissingNode(String),
+    /// The lock already has a node which the journal restores.
+    ExistingNode(String),
+    /// The input does not reference what the journal recorded after pruning,
+    /// so the lock was changed since, or the journal is of another lock.
+    Changed {
+        node: String,
+        input: String,
+        expected: Option<PlannedEdge>,
+        found: Option<PlannedEdge>,
+    },
+}
+
+impl Journal {
+    /// Record what changed between `before` and `after`,
+    /// where `after` is a pruned clone of `before`.
+    pub fn new(before: &LockFile, after: &LockFile) -> Self {
+        let mut edges = Vec::new();
+        let mut removed = Vec::new();
+        for change in diff_locks(before, after) {
+            match change {
+                Change::Edge {
+                    node,
+                    input,
+                    path,
+                    old,
+                    new,
+                } => edges.push(JournalEdge {
+                    path,
+                    node,
+                    input,
+                    old,
+                    new,
+                }),
+                Change::RemovedNode { node, .. } => {
+                    let id = before.find_node(&node).unwrap();
+                    removed.push(JournalNode {
+                        position: before.node_ids().position(|other| other == id).unwrap(),
+                        node: before.raw_node(id).unwrap(),
+                        index: node,
+                    });
+                }
+                // Pruning does not add nodes.
+                Change::AddedNode { .. } => {}
+            }
+        }
+        Self { edges, removed }
+    }
+
+    pub fn from_reader(reader: impl std::io::Read) -> Result<Self> {
+        let deserializer = &mut serde_json::Deserializer::from_reader(reader);
+        serde_path_to_error::deserialize(deserializer).map_err(|error| {
+            if error.inner().is_io() {
+                Error::io("to read the journal", error.into_inner().into())
+            } else {
+                Error::Journal(JournalError::Invalid(error.to_string()))
+            }
+        })
+    }
+
+    /// Put the removed nodes back into the `lock`, and every input back to what it
+    /// referenced before pruning, which makes the lock the same as before pruning.
+    ///
+    /// Every input must reference what the journal recorded after pruning,
+    /// otherwise the `lock` was changed since, and it is left alone.
+    pub fn restore(&self, lock: &mut LockFile) -> Result<(), JournalError> {
+        let mut restored = lock.clone();
+        self.restore_checked(&mut restored)?;
+        *lock = restored;
+        Ok(())
+    }
+
+    // Restoring stops at the first input which does not match,
+    // so it is done to a clone of the lock which is discarded then.
+    fn restore_checked(&self, lock: &mut LockFile) -> Result<(), JournalError> {
+        if let Some(existing) = self
+            .removed
+            .iter()
+            .find(|removed| lock.find_node(&removed.index).is_some())
+        {
+            return Err(JournalError::ExistingNode(existing.index.clone()));
+        }
+        let mut removed = self.removed.iter().collect::<Vec<_>>();
+        removed.sort_by_key(|removed| removed.position);
+        lock.insert_raw_nodes(removed.into_iter().map(|removed| {
+            (
+                removed.position,
+                removed.index.clone(),
+                removed.node.clone(),
+            )
+        }));
+
+        for edge in self.edges.iter().rev() {
+            let id = lock
+                .find_node(&edge.node)
+                .ok_or_else(|| JournalError::MissingNode(edge.node.clone()))?;
+            let found = lock
+                .get_node(id)
+                .unwrap()
+                .get_edge(&edge.input)
+                .map(|found| PlannedEdge::new(lock, found));
+            if found != edge.new {
+                return Err(JournalError::Changed {
+                    node: edge.node.clone(),
+                    input: edge.input.clone(),
+                    expected: edge.new.clone(),
+                    found,
+                });
+            }
+            let old = edge
+                .old
+                .as_ref()
+                .map(|old| to_edge(lock, old))
+                .transpose()?;
+            let node = lock.get_node_mut(id).unwrap();
+            match (old, node.get_edge_mut(&edge.input)) {
+                (Some(old), Some(current)) => *current = old,
+                (Some(old), None) => {
+                    node.insert_edge(edge.input.clone(), old);
+                }
+                (None, _) => {
+                    node.remove_edge(&edge.input);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The journal as human readable text, one line for every input as it is restored,
+/// such as `~ 'hyprcursor/nixpkgs': follows 'nixpkgs' -> 'nixpkgs_2'`,
+/// followed by one for every node which is put back.
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for edge in &self.edges {
+            let path = match &edge.path {
+                Some(path) => path.join("/"),
+                None => format!("{}/{}", edge.node, edge.input),
+            };
+            writeln!(
+                f,
+                "~ '{path}': {} -> {}",
+                DisplayEdge(&edge.new),
+                DisplayEdge(&edge.old)
+            )?;
+        }
+        for removed in &self.removed {
+            match removed.node.as_locked() {
+                Some(node) => writeln!(f, "+ node '{}' ({})", removed.index, node.original())?,
+                None => writeln!(f, "+ node '{}'", removed.index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_edge(lock: &LockFile, edge: &PlannedEdge) -> Result<NodeEdge, JournalError> {
+    match edge {
+        PlannedEdge::Indexed(index) => lock
+            .find_node(index)
+            .map(NodeEdge::Indexed)
+            .ok_or_else(|| JournalError::MissingNode(index.clone())),
+        PlannedEdge::Follows(path) => Ok(NodeEdge::Follows(path.clone())),
+    }
+}
+
+struct DisplayEdge<'a>(&'a Option<PlannedEdge>);
+
+impl fmt::Display for DisplayEdge<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(edge) => write!(f, "{edge}"),
+            None => write!(f, "no input"),
+        }
+    }
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(error) => write!(f, "failed to parse the journal: {error}"),
+            Self::MissingNode(node) => write!(f, "the lock has no node '{node}'"),
+            Self::ExistingNode(node) => {
+                write!(
+                    f,
+                    "the lock already has the node '{node}' which the journal restores"
+                )
+            }
+            Self::Changed {
+                node,
+                input,
+                expected,
+                found,
+            } => write!(
+                f,
+                "the input '{input}' of node '{node}' references {}, \
+                but the journal expects {}",
+                DisplayEdge(found),
+                DisplayEdge(expected)
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hoist::hoist_shared_inputs;
+    use crate::prune::{
+        dedupe_identical_nodes, normalize_follows_paths, prune_orphan_nodes,
+        substitute_flake_inputs_with_follows, SubstituteOptions,
+    };
+    use crate::testing::*;
+
+    #[test]
+    fn restore_journal_after_pruning() {
+        let original = read_sample(HYPRLAND_LOCK_NO_FOLLOWS);
+        let options = SubstituteOptions {
+            depth: None,
+            ..Default::default()
+        };
+        let mut pruned = original.clone();
+        hoist_shared_inputs(&mut pruned, &options, 2);
+        let mut substitutions = substitute_flake_inputs_with_follows(&mut pruned, &options);
+        substitutions.extend(dedupe_identical_nodes(&mut pruned));
+        prune_orphan_nodes(&mut pruned);
+        normalize_follows_paths(&mut pruned, &original, &substitutions, &options.rules).unwrap();
+
+        let journal = Journal::new(&original, &pruned);
+        let json = serde_json::to_string(&journal).unwrap();
+        let journal = Journal::from_reader(json.as_bytes()).unwrap();
+        assert!(!journal.removed.is_empty());
+
+        let pruned_json = serde_json::to_string(&pruned).unwrap();
+        let mut restored: LockFile = serde_json::from_str(&pruned_json).unwrap();
+        journal.restore(&mut restored).unwrap();
+        assert_eq!(
+            serde_json::to_string(&restored).unwrap(),
+            serde_json::to_string(&original).unwrap(),
+            "restoring should reconstruct the lock exactly, including the order of nodes"
+        );
+
+        assert!(matches!(
+            journal.restore(&mut restored),
+            Err(JournalError::ExistingNode(_))
+        ));
+
+        // An input changed since pruning is only found once the nodes are put back.
+        let mut changed: LockFile = serde_json::from_str(&pruned_json).unwrap();
+        let edge = journal.edges.last().unwrap();
+        let id = changed.find_node(&edge.node).unwrap();
+        changed
+            .get_node_mut(id)
+            .unwrap()
+            .insert_edge(edge.input.clone(), NodeEdge::from_iter(["elsewhere"]));
+        let unchanged = changed.clone();
+        assert!(matches!(
+            journal.restore(&mut changed),
+            Err(JournalError::Changed { .. })
+        ));
+        assert_eq!(
+            changed, unchanged,
+            "a failed restore should leave the lock alone"
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Imitate Nix flake input following behavior as a post-process,
+//! so that you can stop manually maintaining tedious connections
+//! between many flake inputs.
+//!
+//! The lock file model lives in [`flake_lock`].
+//! [`prune`] redirects transitive inputs to the root inputs and removes orphaned nodes,
+//! [`resolve`] unifies different revisions of the same source by a policy,
+//! [`rules`] decide which inputs may be redirected at all,
+//! [`hoist`] promotes transitive inputs shared by several root inputs to the root,
+//! [`count`] counts references to nodes, and [`config`] generates the equivalent
+//! `follows` declarations for a `flake.nix`.
+//! The [`settings`] of a project are read from a `.allfollow.toml`.
+//! What pruning does can be recorded as a [`plan`] and applied later,
+//! and [`explain`] tells why a node was kept or removed by it.
+//! [`check`] compares a lock to what pruning makes of it, for CI.
+//! A [`journal`] records what pruning changed, so that it can be undone.
+//! Files are replaced through an [`AtomicFile`], so that they are never left half written.
+//! Failures are reported as an [`Error`], each class of which has its own
+//! process exit code, see [`Error::exit_code`].
+
+pub mod atomic_file;
+pub mod check;
+pub mod config;
+pub mod count;
+pub mod error;
+pub mod explain;
+pub mod flake_lock;
+pub mod hoist;
+pub mod journal;
+pub mod plan;
+pub mod prune;
+pub mod resolve;
+pub mod rules;
+pub mod settings;
+#[cfg(test)]
+mod testing;
+
+pub use atomic_file::AtomicFile;
+pub use check::{diff_locks, Change};
+pub use config::{flake_follows_config, update_flake_nix, write_flake_follows_config, Markers};
+pub use count::FlakeNodeVisits;
+pub use error::{Error, Result};
+pub use explain::{explain_node, Explanation};
+pub use flake_lock::{LockFile, Node, NodeEdge, NodeId};
+pub use hoist::{hoist_shared_inputs, Hoist};
+pub use journal::Journal;
+pub use plan::Plan;
+pub use prune::{
+    dedupe_identical_nodes, node_mismatches, normalize_follows_paths, prune_orphan_nodes,
+    substitute_flake_inputs_with_follows, substitute_node_inputs_with_root_inputs, Mismatch,
+    Reason, Safety, SubstituteOptions, Substitution,
+};
+pub use resolve::{resolve_duplicate_sources, Resolution, ResolvePolicy};
+pub use rules::{FollowRules, InputAlias, InputPattern, RootAliases};
+pub use settings::Settings;
+
+static EXPECT_ROOT_EXIST: &str = "the root node to exist";
@@ -1,36 +1,62 @@
 mod cli_args;
-mod flake_lock;
 mod fmt_colors;
 
-use std::io::Write;
-use std::iter::repeat;
-
-use bpaf::Bpaf;
-use cli_args::{Input, Output};
-use flake_lock::{
-    LockFile, Node, NodeEdge, NodeEdgeRef as _, MAX_SUPPORTED_LOCK_VERSION,
-    MIN_SUPPORTED_LOCK_VERSION,
+use allfollow::explain::Explanation;
+use allfollow::flake_lock::{LockFile, LockIssue, Node, NodeEdge, NodeId};
+use allfollow::{
+    flake_follows_config, update_flake_nix, Error, FlakeNodeVisits, FollowRules, Hoist, InputAlias,
+    InputPattern, Journal, Plan, Reason, ResolvePolicy, Result, RootAliases, Safety, Settings,
+    SubstituteOptions, Substitution,
 };
-use indexmap::IndexMap;
+use bpaf::{Bpaf, Parser};
+use cli_args::{Input, Output, PatternList, PlanFormat};
 use owo_colors::OwoColorize;
 use serde::Serialize;
 use serde_json::Serializer;
-
-static EXPECT_ROOT_EXIST: &str = "the root node to exist";
+use std::io::{self, Write};
+use std::path::PathBuf;
 
 /// Imitate Nix flake input following behavior as a post-process,
 /// so that you can stop manually maintaining tedious connections
 /// between many flake inputs.
 /// This small tool aims to replace every instance of
 /// `inputs.*.inputs.*.follows = "*";` in your `flake.nix` with automation.
+/// Settings are read from a `.allfollow.toml` next to the `flake.lock`,
+/// and overridden by `ALLFOLLOW_*` environment variables and the options.
 #[derive(Debug, Clone, Bpaf)]
 #[bpaf(options, generate(parse_command_env_args))]
 enum Command {
     #[bpaf(command("prune"))]
     Prune {
-        /// Do not imitate `inputs.*.follows`, reference node indices instead
-        #[bpaf(long, long("indexed"))]
-        no_follows: bool,
+        //
+        #[bpaf(external(prune_options))]
+        prune_opts: PruneOptions,
+        /// Write what pruning would do as a plan instead of the pruned lock,
+        /// either as json (for `apply`) or as text, ignoring `--in-place`
+        #[bpaf(long, argument("FORMAT"))]
+        plan: Option<PlanFormat>,
+        /// Also write a journal of every redirected input and removed node to `JOURNAL`,
+        /// from which `restore` reconstructs the lock from before pruning,
+        /// ignored with `--plan` as nothing is pruned then.
+        /// `JOURNAL` must not exist yet, even with `--force`, so that no journal is lost
+        #[bpaf(long, argument("JOURNAL"))]
+        journal: Option<PathBuf>,
+        /// Do not minify the output JSON
+        #[bpaf(short('p'), long)]
+        pretty: bool,
+        //
+        #[bpaf(external(output_options))]
+        output_opts: OutputOptions,
+        /// The path of `flake.lock` to read, or `-` to read from standard input.
+        /// If unspecified, defaults to the current directory.
+        #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
+        lock_file: Input,
+    },
+    #[bpaf(command("apply"))]
+    Apply {
+        /// The path of a plan written by `prune --plan json`, or `-` to read from standard input
+        #[bpaf(long("plan"), argument("PLAN"))]
+        plan_file: Input,
         /// Do not minify the output JSON
         #[bpaf(short('p'), long)]
         pretty: bool,
@@ -42,11 +68,53 @@ enum Command {
         #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
         lock_file: Input,
     },
+    #[bpaf(command("restore"))]
+    Restore {
+        /// The path of a journal written by `prune --journal`, or `-` to read from standard input
+        #[bpaf(long("journal"), argument("JOURNAL"))]
+        journal_file: Input,
+        /// Do not minify the output JSON
+        #[bpaf(short('p'), long)]
+        pretty: bool,
+        //
+        #[bpaf(external(output_options))]
+        output_opts: OutputOptions,
+        /// The path of the pruned `flake.lock` to read, or `-` to read from standard input.
+        /// If unspecified, defaults to the current directory.
+        #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
+        lock_file: Input,
+    },
+    #[bpaf(command("explain"))]
+    Explain {
+        //
+        #[bpaf(external(prune_options))]
+        prune_opts: PruneOptions,
+        /// The index of the node to explain, or an input path such as `hyprland/nixpkgs`
+        #[bpaf(positional("NODE"))]
+        node: String,
+        /// The path of `flake.lock` to read, or `-` to read from standard input.
+        /// If unspecified, defaults to the current directory.
+        #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
+        lock_file: Input,
+    },
+    #[bpaf(command("check"))]
+    Check {
+        //
+        #[bpaf(external(prune_options))]
+        prune_opts: PruneOptions,
+        /// The path of `flake.lock` to read, or `-` to read from standard input.
+        /// If unspecified, defaults to the current directory.
+        #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
+        lock_file: Input,
+    },
     #[bpaf(command("count"))]
     Count {
         /// Show the data as JSON.
         #[bpaf(short('j'), long)]
         json: bool,
+        /// With `--json`, count distinct referencing nodes instead of paths from the root
+        #[bpaf(long)]
+        parents: bool,
         /// Do not minify the output JSON
         #[bpaf(short('p'), long)]
         pretty: bool,
@@ -58,11 +126,24 @@ enum Command {
         #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
         lock_file: Input,
     },
+    #[bpaf(command("validate"))]
+    Validate {
+        /// Fail on warnings too, such as orphaned nodes or cycles of `follows`
+        #[bpaf(long)]
+        strict: bool,
+        /// The path of `flake.lock` to read, or `-` to read from standard input.
+        /// If unspecified, defaults to the current directory.
+        #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
+        lock_file: Input,
+    },
     #[bpaf(command("config"))]
     Config {
         /// Modify the `flake.nix` file in the same directory as the lock file.
         #[bpaf(short('I'), long)]
         in_place: bool,
+        //
+        #[bpaf(external(rule_options))]
+        rule_opts: RuleOptions,
         /// The path of `flake.lock` to read, or `-` to read from standard input.
         /// If unspecified, defaults to the current directory.
         #[bpaf(positional("INPUT"), fallback(Input::from("./flake.lock")))]
@@ -70,6 +151,91 @@ enum Command {
     },
 }
 
+/// Options deciding how inputs are redirected:
+#[derive(Debug, Clone, Bpaf)]
+struct PruneOptions {
+    /// Do not imitate `inputs.*.follows`, reference node indices instead
+    #[bpaf(long, long("indexed"))]
+    no_follows: bool,
+    /// Also redirect the inputs of nodes deeper in the graph, not only those of the root inputs
+    #[bpaf(long)]
+    deep: bool,
+    /// Like `--deep`, but only redirect inputs of nodes at most N levels below the root
+    #[bpaf(long, argument("N"))]
+    depth: Option<usize>,
+    /// Redirect inputs without a root input of the same name to one with the same source
+    #[bpaf(long)]
+    match_source: bool,
+    /// Unify nodes of the same source and branch or tag by picking one revision:
+    /// newest, oldest, root or majority
+    #[bpaf(long, argument("POLICY"))]
+    resolve: Option<ResolvePolicy>,
+    /// Merge nodes which lock identical content, regardless of their names
+    #[bpaf(long)]
+    dedupe: bool,
+    /// Compare the type, source, `dir` and `flake` flag of nodes to the root inputs
+    /// before redirecting to them: off, warn or strict (refuse to redirect)
+    #[bpaf(long, argument("MODE"))]
+    safety: Option<Safety>,
+    /// Promote transitive inputs which several root inputs share to new root inputs,
+    /// and print the declarations to add to `flake.nix` for them
+    #[bpaf(long)]
+    hoist: bool,
+    /// Like `--hoist`, but only promote inputs shared by at least N root inputs
+    /// (default 2, and less than 2 is taken as 2)
+    #[bpaf(long, argument("N"))]
+    hoist_min: Option<usize>,
+    //
+    #[bpaf(external(rule_options))]
+    rule_opts: RuleOptions,
+}
+
+/// Options deciding which inputs are followed:
+#[derive(Debug, Clone, Bpaf)]
+struct RuleOptions {
+    /// Never follow inputs matching the PATTERN, such as `hyprland/nixpkgs`,
+    /// where `*` matches within a segment, `**` matches any segments,
+    /// and a pattern without `/` matches the input name of any node
+    #[bpaf(long, argument("PATTERN"))]
+    exclude: Vec<InputPattern>,
+    /// Only follow inputs matching any of the comma separated PATTERNS
+    #[bpaf(long, argument("PATTERNS"))]
+    only: Vec<PatternList>,
+    /// Never follow the inputs of the node with the index NODE
+    #[bpaf(long, argument("NODE"))]
+    keep: Vec<String>,
+    /// Make inputs matching the PATTERN follow the root input TARGET instead,
+    /// such as `nixpkgs-lib=nixpkgs` or `hyprland/nixpkgs=nixpkgs-unstable`
+    #[bpaf(long, argument("PATTERN=TARGET"))]
+    alias: Vec<InputAlias>,
+    /// Leave inputs which already follow another input as they are,
+    /// and only redirect those which reference a node index
+    #[bpaf(long)]
+    preserve_follows: bool,
+    /// When a root input is itself a `follows` alias of another, make inputs follow:
+    /// target (what the alias follows) or alias (the alias itself)
+    #[bpaf(long, argument("MODE"))]
+    root_aliases: Option<RootAliases>,
+}
+
+impl RuleOptions {
+    /// Add the rules from the command line to those of the settings,
+    /// with the aliases from the command line taking precedence.
+    fn merge_into(self, mut rules: FollowRules) -> FollowRules {
+        rules.exclude.extend(self.exclude);
+        rules
+            .only
+            .extend(self.only.into_iter().flat_map(|list| list.0));
+        rules.keep.extend(self.keep);
+        rules.aliases.splice(0..0, self.alias);
+        rules.preserve_follows |= self.preserve_follows;
+        if let Some(root_aliases) = self.root_aliases {
+            rules.root_aliases = root_aliases;
+        }
+        rules
+    }
+}
+
 /// Generic options for output handling:
 #[derive(Debug, Clone, Bpaf)]
 struct OutputOptions {
@@ -82,14 +248,50 @@ struct OutputOptions {
     /// Path of the file to write, set to `-` for stdout (default)
     #[bpaf(short('o'), long, argument("OUTPUT"), fallback(Output::Stdout))]
     output: Output,
+    //
+    #[bpaf(external(backup))]
+    backup: Option<String>,
+}
+
+/// The suffix of the backup file when `--backup` is given without one.
+const DEFAULT_BACKUP_SUFFIX: &str = ".bak";
+
+/// `--backup` or `--backup=SUFFIX`, which must be adjacent so that
+/// `--backup flake.lock` does not take the input path as the suffix.
+fn backup() -> impl Parser<Option<String>> {
+    let with_suffix = bpaf::long("backup")
+        .help(
+            "Keep the file which is overwritten, with `SUFFIX` (default `.bak`) \
+            appended to its name",
+        )
+        .argument::<String>("SUFFIX")
+        .adjacent();
+    let default = bpaf::long("backup")
+        .req_flag(DEFAULT_BACKUP_SUFFIX.to_owned())
+        .hide();
+    bpaf::construct!([with_suffix, default]).optional()
 }
 
 impl Command {
     fn from_env() -> Self {
         let mut args = parse_command_env_args().run();
-        #[allow(clippy::single_match)]
         match &mut args {
             Command::Prune {
+                plan: Some(_),
+                output_opts,
+                ..
+            } => output_opts.in_place = false,
+            Command::Prune {
+                lock_file,
+                output_opts,
+                ..
+            }
+            | Command::Apply {
+                lock_file,
+                output_opts,
+                ..
+            }
+            | Command::Restore {
                 lock_file,
                 output_opts,
                 ..
@@ -104,453 +306,775 @@ impl Command {
                     output_opts.overwrite = true;
                 }
             }
-            Command::Config { .. } => {}
+            Command::Explain { .. }
+            | Command::Check { .. }
+            | Command::Validate { .. }
+            | Command::Config { .. } => {}
         };
         args
     }
 }
 
 fn main() {
-    match Command::from_env() {
+    if let Err(e) = run(Command::from_env()) {
+        elogln!(:bold :red "error:", (e));
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
         Command::Prune {
-            no_follows,
+            prune_opts,
+            plan,
+            journal,
             lock_file,
             pretty,
-            output_opts:
-                OutputOptions {
-                    in_place: _,
-                    overwrite,
-                    output,
-                },
+            output_opts,
         } => {
-            let mut lock = read_flake_lock(lock_file);
-
-            let node_hits = FlakeNodeVisits::count_from_index(&lock, lock.root_index());
-            eprintln!();
-            elogln!(:bold :bright_magenta "Flake input nodes' reference counts:"; &node_hits);
-
-            substitute_flake_inputs_with_follows(&lock, no_follows);
+            let settings = load_settings(&lock_file)?;
+            let mut lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
+            let original = lock.clone();
+
+            let (substitutions, removed) = prune_lock(&mut lock, prune_opts, &settings)?;
+
+            let pretty = pretty || settings.pretty;
+            match plan {
+                Some(PlanFormat::Json) => {
+                    let plan = Plan::new(&original, &lock, &substitutions, &removed);
+                    serialize_to_json_output(&plan, output_opts, pretty)?;
+                }
+                Some(PlanFormat::Text) => {
+                    let plan = Plan::new(&original, &lock, &substitutions, &removed);
+                    write_text_output(&plan.to_string(), output_opts)?;
+                }
+                None => {
+                    // The journal is written first, so that the original nodes
+                    // are never lost by writing the pruned lock in place.
+                    if let Some(journal) = journal {
+                        let journal_opts = OutputOptions {
+                            in_place: false,
+                            overwrite: false,
+                            output: Output::File(journal),
+                            backup: None,
+                        };
+                        let journal = Journal::new(&original, &lock);
+                        serialize_to_json_output(journal, journal_opts, pretty)?;
+                    }
+                    serialize_to_json_output(&lock, output_opts, pretty)?;
+                }
+            }
+        }
+        Command::Apply {
+            plan_file,
+            pretty,
+            lock_file,
+            output_opts,
+        } => {
+            let settings = load_settings(&lock_file)?;
+            let mut lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
+            let reader = plan_file
+                .open()
+                .map_err(|e| Error::io(format!("to read {}", describe_input(&plan_file)), e))?;
+            let plan = Plan::from_reader(reader)?;
+
+            elogln!(:bold :bright_magenta .("Applying the plan from {}:", describe_input(&plan_file)));
+            eprint!("{plan}");
+            plan.apply(&mut lock).map_err(Error::Plan)?;
             eprintln!();
-            prune_orphan_nodes(&mut lock);
 
-            eprintln!();
-            let node_hits = FlakeNodeVisits::count_from_index(&lock, lock.root_index());
-            elog!(
-                :bold (:bright_magenta "Flake input nodes' reference counts", :bright_green "after successful pruning" :bright_magenta ":");
-                &node_hits
-            );
+            serialize_to_json_output(&lock, output_opts, pretty || settings.pretty)?;
+        }
+        Command::Restore {
+            journal_file,
+            pretty,
+            lock_file,
+            output_opts,
+        } => {
+            let settings = load_settings(&lock_file)?;
+            let mut lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
+            let reader = journal_file
+                .open()
+                .map_err(|e| Error::io(format!("to read {}", describe_input(&journal_file)), e))?;
+            let journal = Journal::from_reader(reader)?;
+
+            elogln!(:bold :bright_magenta .("Restoring the journal from {}:", describe_input(&journal_file)));
+            eprint!("{journal}");
+            journal.restore(&mut lock).map_err(Error::Journal)?;
             eprintln!();
 
-            serialize_to_json_output(&lock, output, overwrite, pretty)
+            serialize_to_json_output(&lock, output_opts, pretty || settings.pretty)?;
+        }
+        Command::Explain {
+            prune_opts,
+            node,
+            lock_file,
+        } => {
+            let settings = load_settings(&lock_file)?;
+            let mut lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
+            let original = lock.clone();
+            let Some(id) = allfollow::explain::find_node_or_path(&lock, &node) else {
+                return Err(Error::NodeNotFound { node });
+            };
+
+            let (substitutions, removed) = prune_lock(&mut lock, prune_opts, &settings)?;
+            let explanation =
+                allfollow::explain_node(&original, &lock, &substitutions, &removed, id);
+            log_explanation(&original, &lock, &explanation);
+        }
+        Command::Check {
+            prune_opts,
+            lock_file,
+        } => {
+            let settings = load_settings(&lock_file)?;
+            let mut lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
+            let original = lock.clone();
+
+            prune_lock(&mut lock, prune_opts, &settings)?;
+            let changes = allfollow::diff_locks(&original, &lock);
+            if changes.is_empty() {
+                elogln!(:bold :bright_green "The lock file is fully pruned.");
+                return Ok(());
+            }
+            elogln!(:bold :bright_red .("Pruning would make {} change(s) to the lock file:", changes.len()));
+            for change in &changes {
+                logln!((change));
+            }
+            return Err(Error::NotPruned {
+                changes: changes.len(),
+            });
         }
         Command::Count {
             json,
+            parents,
             pretty,
             lock_file,
-            output_opts:
-                OutputOptions {
-                    in_place: _,
-                    overwrite,
-                    output,
-                },
+            output_opts,
         } => {
-            let lock = read_flake_lock(lock_file);
-            let node_hits = FlakeNodeVisits::count_from_index(&lock, lock.root_index());
-            if json {
-                serialize_to_json_output(&*node_hits, output, overwrite, pretty)
+            let settings = load_settings(&lock_file)?;
+            let pretty = pretty || settings.pretty;
+            let lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
+            let node_hits = FlakeNodeVisits::count_from_node(&lock, lock.root_id());
+            if json && parents {
+                serialize_to_json_output(node_hits.parents(), output_opts, pretty)?;
+            } else if json {
+                serialize_to_json_output(&*node_hits, output_opts, pretty)?;
             } else {
-                logln!(:bold :bright_magenta "Flake input nodes' reference counts:"; &node_hits)
+                logln!(:bold :bright_magenta "Flake input nodes' reference counts:"; (DisplayNodeVisits(&node_hits)))
+            }
+        }
+        Command::Validate { strict, lock_file } => {
+            let lock = read_flake_lock(&lock_file)?;
+            let warnings = ensure_traversable(&lock)?;
+            if warnings.is_empty() {
+                elogln!(:bold :bright_green "The lock file is valid.");
+            } else if strict {
+                return Err(Error::Integrity(warnings));
+            } else {
+                elogln!(:bold :yellow .("The lock file is valid, with {} warning(s).", warnings.len()));
             }
         }
         Command::Config {
             in_place,
+            rule_opts,
             lock_file,
         } => {
-            let lock = read_flake_lock(lock_file.clone());
+            let settings = load_settings(&lock_file)?;
+            let lock = read_flake_lock(&lock_file)?;
+            ensure_traversable(&lock)?;
 
-            let mut buf = Vec::new();
-            print_flake_follows_config(&lock, &mut buf);
-            let config_output = String::from_utf8(buf).expect("config output to be utf8");
+            let rules = rule_opts.merge_into(settings.rules);
+            let config_output = flake_follows_config(&lock, &rules, &settings.markers);
 
             if in_place {
-                match lock_file {
-                    Input::File(path) => {
-                        let flake_nix_path = path
-                            .parent()
-                            .expect("lock file to have a parent directory")
-                            .join("flake.nix");
-                        update_flake_nix(&flake_nix_path, &config_output);
-                    }
-                    Input::Stdin => {
-                        // For stdin, we default to current directory for flake.nix
-                        let flake_nix_path = std::path::PathBuf::from("flake.nix");
-                        update_flake_nix(&flake_nix_path, &config_output);
-                    }
-                }
+                let flake_nix_path = match lock_file {
+                    Input::File(path) => path.with_file_name("flake.nix"),
+                    // For stdin, we default to current directory for flake.nix
+                    Input::Stdin => std::path::PathBuf::from("flake.nix"),
+                };
+                update_flake_nix(&flake_nix_path, &config_output, &settings.markers)?;
+                eprintln!("Successfully updated flake.nix");
             } else {
                 print!("{}", config_output);
             }
         }
     }
+    Ok(())
 }
 
-fn update_flake_nix(path: &std::path::Path, config: &str) {
-    let content = std::fs::read_to_string(path).unwrap_or_else(|e| {
-        panic!("Failed to read flake.nix at {:?}: {}", path, e);
-    });
-
-    let start_marker = "# START INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY";
-    let end_marker = "# END INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY";
-
-    // Find markers
-    let start_idx = content.find(start_marker);
-    let end_idx = content.find(end_marker);
-
-    match (start_idx, end_idx) {
-        (Some(start), Some(end)) => {
-            if start >= end {
-                panic!("Found markers in flake.nix but START comes after END.");
-            }
-
-            // Find the start of the line for indentation
-            let line_start = content[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-            let indent = &content[line_start..start];
-            // Check if indent is only whitespace
-            if !indent.trim().is_empty() {
-                 // If not whitespace, maybe the marker is inline?
-                 // But requirements said "respect the identation".
-                 // We'll assume the indent is everything from last newline.
-            }
-
-            // Prepare the new block with indentation
-            let indented_config = config
-                .lines()
-                .map(|line| format!("{}{}", indent, line))
-                .collect::<Vec<_>>()
-                .join("\n");
-
-            // We replace everything from start_marker to end_marker + end_marker.len()
-            // The `config` generated by `print_flake_follows_config` will already include markers?
-            // Wait, my plan said `print_flake_follows_config` wraps it in markers.
-
-            // If `config` has markers, I should not double them.
-            // But here I'm replacing from existing marker to existing marker.
-
-            // Let's refine `print_flake_follows_config` first to see what it outputs.
-            // If `print_flake_follows_config` outputs:
-            // # START...
-            // inputs = { ... };
-            // # END...
-
-            // And I replace:
-            // <indent># START...
-            // ...
-            // <indent># END...
-
-            // with `indented_config`.
-
-            // `indented_config` will be:
-            // <indent># START...
-            // <indent>inputs = { ... };
-            // <indent># END...
-
-            // This looks correct.
-
-            let end_of_end_marker = end + end_marker.len();
-
-            // We need to be careful about what we replace.
-            // `start` points to `# START`.
-            // `end` points to `# END`.
-
-            // If I replace `content[start..end_of_end_marker]` with `indented_config`.
-
-            let mut new_content = String::with_capacity(content.len());
-            new_content.push_str(&content[..start]);
-            // Remove the indentation that was part of the original file if we are re-adding it?
-            // No, `content[..start]` includes the `indent` because `start` is where `#` is.
-            // So `content[..start]` ends with `indent`.
-            // If `indented_config` starts with `indent`, we get double indentation for the first line.
-
-            // Correction:
-            // `content[..start]` ends with `indent`.
-            // `indented_config` starts with `indent`.
-            // We should trim the tail of `content[..start]`?
-
-            // Or better: `print_flake_follows_config` returns the block WITHOUT indentation.
-            // We add indentation here.
-
-            // BUT `print_flake_follows_config` is also used for stdout.
-            // For stdout, indentation 0 is fine.
-
-            // If `indented_config` is:
-            // indent + "# START..."
-            // indent + "inputs..."
-
-            // And `content[..start]` is `...newline + indent`.
-            // Replaced: `...newline + indent + indent + "# START..."`. Double indent.
-
-            // So we should replace `content[line_start..end_of_end_marker]`.
-            // `line_start` is start of the line containing `# START`.
-
-            new_content.truncate(line_start);
-            new_content.push_str(&indented_config);
-            new_content.push_str(&content[end_of_end_marker..]);
-
-            std::fs::write(path, new_content).unwrap_or_else(|e| {
-                panic!("Failed to write to flake.nix: {}", e);
-            });
-            eprintln!("Successfully updated flake.nix");
-        }
-        _ => {
-            panic!("Could not find the start and end markers in flake.nix. Please add them manually:\n{}\ninputs = {{ ... }};\n{}", start_marker, end_marker);
+/// The substitutions made by pruning and the nodes it removed, in order.
+type PruneOutcome = (Vec<Substitution>, Vec<(NodeId, Node)>);
+
+/// Resolve, redirect, dedupe and prune the lock as the options ask, then normalize its
+/// `follows` paths, logging every step, and return the substitutions and removed nodes.
+fn prune_lock(
+    lock: &mut LockFile,
+    prune_opts: PruneOptions,
+    settings: &Settings,
+) -> Result<PruneOutcome> {
+    let PruneOptions {
+        no_follows,
+        deep,
+        depth,
+        match_source,
+        resolve,
+        dedupe,
+        safety,
+        hoist,
+        hoist_min,
+        rule_opts,
+    } = prune_opts;
+
+    let node_hits = FlakeNodeVisits::count_from_node(lock, lock.root_id());
+    eprintln!();
+    elogln!(:bold :bright_magenta "Flake input nodes' reference counts:"; (DisplayNodeVisits(&node_hits)));
+
+    let before = lock.clone();
+    let mut substitutions = Vec::new();
+    if let Some(policy) = resolve.or(settings.resolve) {
+        substitutions.extend(resolve_duplicate_sources(lock, policy));
+        eprintln!();
+    }
+    let options = SubstituteOptions {
+        indexed: no_follows,
+        depth: match (deep, depth) {
+            (_, Some(depth)) => Some(depth),
+            (true, None) => None,
+            (false, None) => SubstituteOptions::default().depth,
+        },
+        match_source,
+        rules: rule_opts.merge_into(settings.rules.clone()),
+        safety: safety.or(settings.safety).unwrap_or_default(),
+    };
+    let hoists = match (hoist, hoist_min) {
+        (_, Some(min_root_inputs)) => hoist_shared_inputs(lock, &options, min_root_inputs),
+        (true, None) => hoist_shared_inputs(lock, &options, 2),
+        (false, None) => Vec::new(),
+    };
+    substitutions.extend(substitute_flake_inputs_with_follows(lock, &options));
+    eprintln!();
+    if dedupe {
+        substitutions.extend(dedupe_identical_nodes(lock));
+        eprintln!();
+    }
+    let removed = prune_orphan_nodes(lock);
+    eprintln!();
+    let normalized = normalize_follows_paths(lock, &before, &substitutions, &options.rules)?;
+    substitutions.extend(normalized);
+
+    eprintln!();
+    let node_hits = FlakeNodeVisits::count_from_node(lock, lock.root_id());
+    elog!(
+        :bold (:bright_magenta "Flake input nodes' reference counts", :bright_green "after successful pruning" :bright_magenta ":");
+        (DisplayNodeVisits(&node_hits))
+    );
+    eprintln!();
+
+    if !hoists.is_empty() {
+        elogln!(:bold :bright_magenta "Add the hoisted root inputs to the inputs of your `flake.nix`:");
+        for hoist in &hoists {
+            elogln!((hoist.flake_nix_declaration(lock)));
         }
+        eprintln!();
     }
+
+    Ok((substitutions, removed))
 }
 
-fn read_flake_lock(lock_file: Input) -> LockFile {
+fn read_flake_lock(lock_file: &Input) -> Result<LockFile> {
     let reader = lock_file
         .open()
-        .unwrap_or_else(|e| panic!("Failed to read the input file: {e}"));
-    let deserializer = &mut serde_json::Deserializer::from_reader(reader);
+        .map_err(|e| Error::io(format!("to read {}", describe_input(lock_file)), e))?;
+    LockFile::from_reader(reader)
+}
 
-    let lock: LockFile = {
-        serde_path_to_error::deserialize(deserializer)
-            .unwrap_or_else(|e| panic!("Failed to deserialize the provided flake lock: {e}"))
+/// The settings of the project which the lock file belongs to,
+/// or of the current directory when reading from standard input.
+fn load_settings(lock_file: &Input) -> Result<Settings> {
+    let dir = match lock_file {
+        Input::File(path) => path.parent().filter(|dir| !dir.as_os_str().is_empty()),
+        Input::Stdin => None,
     };
+    Settings::load(dir.unwrap_or(std::path::Path::new(".")))
+}
 
-    if lock.version() < MIN_SUPPORTED_LOCK_VERSION && lock.version() > MAX_SUPPORTED_LOCK_VERSION {
-        panic!(
-            "This program supports lock files between schema versions {} and {} while the flake you have asked to modify is of version {}.",
-            MIN_SUPPORTED_LOCK_VERSION,
-            MAX_SUPPORTED_LOCK_VERSION,
-            lock.version()
-        );
+fn describe_input(input: &Input) -> String {
+    match input {
+        Input::Stdin => "standard input".to_owned(),
+        Input::File(path) => format!("'{}'", path.display()),
     }
+}
 
-    lock
+fn describe_output(output: &Output) -> String {
+    match output {
+        Output::Stdout => "standard output".to_owned(),
+        Output::File(path) => format!("'{}'", path.display()),
+    }
 }
 
-fn serialize_to_json_output(value: impl Serialize, output: Output, overwrite: bool, pretty: bool) {
-    let writer = output
-        .create(!overwrite)
-        .unwrap_or_else(|e| panic!("Could not write to output: {e}"));
+/// Report every issue with the lock, and fail if there are any
+/// which would make it impossible to traverse the node graph.
+/// Report the issues with the `lock`, failing on the fatal ones.
+/// Returns the harmless issues, which are only warnings.
+fn ensure_traversable(lock: &LockFile) -> Result<Vec<LockIssue>> {
+    let issues = lock.validate();
+    if !issues.is_empty() {
+        report_lock_issues(&issues);
+        eprintln!();
+    }
+    let (fatal, warnings): (Vec<_>, Vec<_>) = issues.into_iter().partition(LockIssue::is_fatal);
+    if fatal.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(Error::Integrity(fatal))
+    }
+}
+
+fn report_lock_issues(issues: &[LockIssue]) {
+    elogln!(:bold :bright_red .("Found {} problem(s) with the lock file:", issues.len()));
+    for issue in issues {
+        if issue.is_fatal() {
+            elogln!("-", :red "error:", (issue));
+        } else {
+            elogln!("-", :yellow "warning:", (issue));
+        }
+    }
+}
+
+fn serialize_to_json_output(
+    value: impl Serialize,
+    output_opts: OutputOptions,
+    pretty: bool,
+) -> Result<()> {
+    let OutputOptions {
+        overwrite,
+        output,
+        backup,
+        ..
+    } = output_opts;
+    let context = || format!("to write {}", describe_output(&output));
+    let mut writer = output
+        .create(!overwrite, backup.as_deref())
+        .map_err(|e| Error::io(context(), e))?;
 
     let res = if pretty {
-        value.serialize(&mut Serializer::pretty(writer))
+        value.serialize(&mut Serializer::pretty(&mut writer))
     } else {
-        value.serialize(&mut Serializer::new(writer))
+        value.serialize(&mut Serializer::new(&mut writer))
     };
 
-    if let Err(e) = res {
-        panic!("Failed while serializing to output, file is probably corrupt: {e}")
-    }
+    // The values serialized here cannot fail to serialize,
+    // so any error has come from the writer.
+    res.map_err(io::Error::from)
+        .and_then(|()| writer.finish())
+        .map_err(|e| Error::io(context(), e))
+}
+
+fn write_text_output(text: &str, output_opts: OutputOptions) -> Result<()> {
+    let OutputOptions {
+        overwrite,
+        output,
+        backup,
+        ..
+    } = output_opts;
+    let context = || format!("to write {}", describe_output(&output));
+    let mut writer = output
+        .create(!overwrite, backup.as_deref())
+        .map_err(|e| Error::io(context(), e))?;
+    writer
+        .write_all(text.as_bytes())
+        .and_then(|()| writer.finish())
+        .map_err(|e| Error::io(context(), e))
 }
 
-fn substitute_flake_inputs_with_follows(lock: &LockFile, indexed: bool) {
+fn substitute_flake_inputs_with_follows(
+    lock: &mut LockFile,
+    options: &SubstituteOptions,
+) -> Vec<Substitution> {
     elogln!(:bold :bright_magenta "Redirecting inputs to imitate follows behavior.");
 
-    let root = lock.root().expect(EXPECT_ROOT_EXIST);
-    for (input_name, input_index) in root
-        .iter_edges()
-        .filter_map(|(name, edge)| edge.index().map(|index| (name, index)))
+    let input_paths = lock.input_paths();
+    let substitutions = allfollow::substitute_flake_inputs_with_follows(lock, options);
+    let mut current_node = None;
+    for substitution in substitutions
+        .iter()
+        .filter(|substitution| !matches!(substitution.reason, Reason::Preserved { .. }))
     {
-        elogln!(:bold (:bright_cyan "Replacing inputs for", :green "'{input_name}'"), :dimmed "(" :dimmed :italic "'{input_index}'" :dimmed ")");
-        let input = &*lock
-            .get_node(&*input_index)
-            .expect("a node to exist with this index");
-        substitute_node_inputs_with_root_inputs(lock, input, indexed);
-    }
-}
-
-/// When `indexed == false`, the input replacements all will reference identically
-/// named inputs from the root node. This imitates input following behavior.
-///
-/// Otherwise, if `indexed == true`, the each input replacement will be cloned
-/// verbatim from the root node, most likely retaining a `NodeEdge::Indexed`.
-fn substitute_node_inputs_with_root_inputs(lock: &LockFile, node: &Node, indexed: bool) {
-    let root = lock.root().expect(EXPECT_ROOT_EXIST);
-    for (edge_name, mut edge) in node.iter_edges_mut() {
-        if let Some(root_edge) = root.get_edge(edge_name) {
-            if indexed {
-                let old = std::mem::replace(&mut *edge, (*root_edge).clone());
-                elogln!("-", :yellow "'{edge_name}'", "now references", :italic :purple "'{edge}'", :dimmed "(was '{old}')");
-            } else {
-                let old = std::mem::replace(&mut *edge, NodeEdge::from_iter([edge_name]));
-                elogln!("-", :yellow "'{edge_name}'", "now follows", :green "'{edge}'", :dimmed "(was '{old}')");
-            }
-        } else {
+        let node = substitution.node;
+        if current_node != Some(node) {
+            current_node = Some(node);
+            let input_name = input_paths
+                .get(&node)
+                .map_or_else(String::new, |path| path.join("/"));
             elogln!(
-                :bold (:cyan "No suitable replacement for", :yellow "'{edge_name}'"),
-                :dimmed "(" :dimmed :italic ("'" (lock.resolve_edge(&edge).unwrap()) "'") :dimmed ")"
+                :bold (:bright_cyan "Replacing inputs for", :green "'{input_name}'"),
+                :dimmed "(" :dimmed :italic ("'" (lock.node_index(node)) "'"), :dimmed (node_source(lock, node)) :dimmed ")"
             );
         }
+        log_substitution(lock, substitution);
     }
-}
 
-fn prune_orphan_nodes(lock: &mut LockFile) {
-    elogln!(:bold :bright_magenta "Pruning orphaned nodes from modified lock.");
-
-    let node_hits = FlakeNodeVisits::count_from_index(lock, lock.root_index());
+    let preserved = substitutions
+        .iter()
+        .filter_map(|substitution| match &substitution.reason {
+            Reason::Preserved { follows } => Some((substitution, follows)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if !preserved.is_empty() {
+        eprintln!();
+        elogln!(:bold :bright_magenta "Preserved inputs which already follow another input:");
+        for (Substitution { node, input, .. }, follows) in preserved {
+            let input_path = input_paths.get(node).map_or_else(
+                || input.clone(),
+                |path| format!("{}/{input}", path.join("/")),
+            );
+            elogln!("-", :yellow "'{input_path}'", "follows", :green ("'" (follows.join("/")) "'"));
+        }
+    }
 
-    let dead_nodes = node_hits
-        .into_inner()
-        .into_iter()
-        .filter(|&(_, count)| count == 0)
-        .map(|(index, _)| index.to_owned())
+    let unified = substitutions
+        .iter()
+        .filter_map(|substitution| match &substitution.reason {
+            Reason::SameSource { root_input } => Some((substitution, root_input)),
+            _ => None,
+        })
         .collect::<Vec<_>>();
+    if !unified.is_empty() {
+        eprintln!();
+        elogln!(:bold :bright_magenta "Unified inputs with root inputs of a different name by their source:");
+        for (
+            Substitution {
+                node, input, old, ..
+            },
+            root_input,
+        ) in unified
+        {
+            let input_path = input_paths.get(node).map_or_else(
+                || input.clone(),
+                |path| format!("{}/{input}", path.join("/")),
+            );
+            elogln!("-", :yellow "'{input_path}'", "with", :green "'{root_input}'", :dimmed "(" :dimmed (edge_source(lock, old)) :dimmed ")");
+        }
+    }
 
-    for index in dead_nodes {
-        lock.remove_node(&index);
-        elogln!("- removed", :red "'{index}'");
+    let mismatched = substitutions
+        .iter()
+        .filter(|substitution| !substitution.mismatches.is_empty())
+        .collect::<Vec<_>>();
+    if !mismatched.is_empty() {
+        eprintln!();
+        elogln!(:bold :bright_yellow "Inputs which differ from the root inputs they follow:");
+        for Substitution {
+            node,
+            input,
+            new,
+            reason,
+            mismatches,
+            ..
+        } in mismatched
+        {
+            let input_path = input_paths.get(node).map_or_else(
+                || input.clone(),
+                |path| format!("{}/{input}", path.join("/")),
+            );
+            match (new, reason) {
+                (None, Reason::Mismatched { root_input }) => {
+                    elogln!("-", :yellow "'{input_path}'", :red "refused", :green "'{root_input}'");
+                }
+                (Some(new), _) => {
+                    elogln!("-", :yellow "'{input_path}'", "redirected anyway to", :green ("'" (new.display(lock)) "'"));
+                }
+                (None, _) => continue,
+            }
+            for mismatch in mismatches {
+                elogln!("  -", (mismatch));
+            }
+        }
     }
+    substitutions
 }
 
-fn recurse_inputs(lock: &LockFile, index: String, op: &mut impl FnMut(String)) {
-    let node = lock.get_node(&index).unwrap();
-    op(index);
-    for (_, edge) in node.iter_edges() {
-        let index = lock.resolve_edge(&edge).unwrap();
-        recurse_inputs(lock, index, op);
+fn resolve_duplicate_sources(lock: &mut LockFile, policy: ResolvePolicy) -> Vec<Substitution> {
+    elogln!(:bold :bright_magenta .("Resolving revisions of the same source by the '{policy}' policy."));
+
+    let mut substitutions = Vec::new();
+    for resolution in allfollow::resolve_duplicate_sources(lock, policy) {
+        let winner = resolution.winner;
+        let rev = lock
+            .get_node(winner)
+            .and_then(|node| node.as_locked())
+            .and_then(|node| node.locked().rev.clone())
+            .unwrap_or_default();
+        elogln!(
+            :bold (:bright_cyan "Picked", :green ("'" (lock.node_index(winner)) "'")),
+            :dimmed "(" :dimmed (node_source(lock, winner)), :dimmed :italic (rev) :dimmed "):",
+            (resolution.why)
+        );
+        for Substitution {
+            node, input, old, ..
+        } in &resolution.substitutions
+        {
+            let input_name = format!("{}/{input}", lock.node_index(*node));
+            elogln!("-", :yellow "'{input_name}'", "now references", :italic :purple ("'" (lock.node_index(winner)) "'"), :dimmed ("(was '" (old.display(lock)) "')"));
+        }
+        substitutions.extend(resolution.substitutions);
     }
+    substitutions
 }
 
-fn print_flake_follows_config(lock: &LockFile, writer: &mut impl Write) {
-    writeln!(writer, "# START INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY").ok();
-    writeln!(writer, "inputs = {{").ok();
-    let root = lock.root().expect(EXPECT_ROOT_EXIST);
-    // Identify root inputs
-    let root_inputs: std::collections::HashSet<String> = root
-        .iter_edges()
-        .map(|(name, _)| name.to_string())
-        .collect();
-
-    // Start traversal from root inputs
-    for (input_name, edge) in root.iter_edges() {
-        if let Some(index) = edge.index() {
-            traverse_and_print_config(
-                lock,
-                &root_inputs,
-                &index,
-                vec![input_name.to_string()],
-                &mut vec![index.to_string()],
-                writer,
-            );
+fn hoist_shared_inputs(
+    lock: &mut LockFile,
+    options: &SubstituteOptions,
+    min_root_inputs: usize,
+) -> Vec<Hoist> {
+    elogln!(:bold :bright_magenta .("Hoisting inputs shared by at least {min_root_inputs} root inputs."));
+
+    let hoists = allfollow::hoist_shared_inputs(lock, options, min_root_inputs);
+    for Hoist { name, node, paths } in &hoists {
+        elogln!(
+            :bold (:bright_cyan "Hoisted", :green ("'" (name) "'")),
+            "as a root input referencing", :italic :purple ("'" (lock.node_index(*node)) "'"),
+            :dimmed "(" :dimmed (node_source(lock, *node)) :dimmed "),",
+            "shared by:"
+        );
+        for path in paths {
+            elogln!("-", :yellow ("'" (path.join("/")) "'"));
         }
     }
-    writeln!(writer, "}};").ok();
-    write!(writer, "# END INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY").ok();
+    if hoists.is_empty() {
+        elogln!("-", :dimmed "no shared inputs to hoist");
+    }
+    eprintln!();
+    hoists
 }
 
-fn traverse_and_print_config(
-    lock: &LockFile,
-    root_inputs: &std::collections::HashSet<String>,
-    current_node_index: &str,
-    current_path: Vec<String>,
-    visited_indices: &mut Vec<String>, // To detect cycles in the current path
-    writer: &mut impl Write,
-) {
-    let node = lock.get_node(current_node_index).expect("node exists");
-
-    for (edge_name, edge) in node.iter_edges() {
-        // If the edge name matches a root input, print the config
-        if root_inputs.contains(edge_name) {
-            let mut config_path = current_path.clone();
-            config_path.push(edge_name.to_string());
-            // Construct string like B.inputs.C.inputs.nixpkgs.follows = "nixpkgs"
-            // Path elements join with ".inputs."
-            let path_str = config_path.join(".inputs.");
-            writeln!(writer, "    {}.follows = \"{}\";", path_str, edge_name).ok();
-
-            // If we are configuring it to follow, we essentially stop traversing this branch *as if* it was the root input.
-            continue;
-        }
+fn dedupe_identical_nodes(lock: &mut LockFile) -> Vec<Substitution> {
+    elogln!(:bold :bright_magenta "Merging nodes which lock identical content.");
+
+    let substitutions = allfollow::dedupe_identical_nodes(lock);
+    for Substitution {
+        node,
+        input,
+        old,
+        new,
+        ..
+    } in &substitutions
+    {
+        let Some(new) = new else { continue };
+        let input_name = format!("{}/{input}", lock.node_index(*node));
+        elogln!("-", :yellow "'{input_name}'", "now references", :italic :purple ("'" (new.display(lock)) "'"), :dimmed ("(was '" (old.display(lock)) "',"), :dimmed (edge_source(lock, old)) :dimmed ")");
+    }
+    substitutions
+}
 
-        // If not following a root input, we recurse.
-        if let Some(child_index) = lock.resolve_edge(&edge) {
-            if !visited_indices.contains(&child_index) {
-                visited_indices.push(child_index.clone());
-                let mut new_path = current_path.clone();
-                new_path.push(edge_name.to_string());
-                traverse_and_print_config(
-                    lock,
-                    root_inputs,
-                    &child_index,
-                    new_path,
-                    visited_indices,
-                    writer,
+fn log_substitution(lock: &LockFile, substitution: &Substitution) {
+    let Substitution {
+        input,
+        old,
+        new,
+        reason,
+        ..
+    } = substitution;
+    match new {
+        Some(edge @ NodeEdge::Indexed(_)) => {
+            elogln!("-", :yellow "'{input}'", "now references", :italic :purple ("'" (edge.display(lock)) "'"), :dimmed ("(was '" (old.display(lock)) "',"), :dimmed (edge_source(lock, old)) :dimmed ")");
+        }
+        Some(edge @ NodeEdge::Follows(_)) => {
+            elogln!("-", :yellow "'{input}'", "now follows", :green ("'" (edge.display(lock)) "'"), :dimmed ("(was '" (old.display(lock)) "',"), :dimmed (edge_source(lock, old)) :dimmed ")");
+        }
+        None => {
+            let target = lock.resolve_edge(old).unwrap();
+            if let Reason::Excluded(exclusion) = reason {
+                elogln!(
+                    :bold (:cyan "Not replacing", :yellow "'{input}'"),
+                    :dimmed "(" :dimmed :italic ("'" (lock.node_index(target)) "'"), :dimmed (node_source(lock, target)) :dimmed "):",
+                    (exclusion)
+                );
+            } else if let Reason::Mismatched { root_input } = reason {
+                elogln!(
+                    :bold (:cyan "Not replacing", :yellow "'{input}'"),
+                    :dimmed "(" :dimmed :italic ("'" (lock.node_index(target)) "'"), :dimmed (node_source(lock, target)) :dimmed "):",
+                    "differs from", :green "'{root_input}'"
+                );
+            } else {
+                elogln!(
+                    :bold (:cyan "No suitable replacement for", :yellow "'{input}'"),
+                    :dimmed "(" :dimmed :italic ("'" (lock.node_index(target)) "'"), :dimmed (node_source(lock, target)) :dimmed ")"
                 );
-                visited_indices.pop();
             }
         }
     }
 }
 
+fn normalize_follows_paths(
+    lock: &mut LockFile,
+    before: &LockFile,
+    substitutions: &[Substitution],
+    rules: &FollowRules,
+) -> Result<Vec<Substitution>> {
+    elogln!(:bold :bright_magenta "Normalizing follows paths of the pruned lock.");
+
+    let input_paths = lock.input_paths();
+    let substitutions = allfollow::normalize_follows_paths(lock, before, substitutions, rules)
+        .map_err(|issues| {
+            report_lock_issues(&issues);
+            Error::Integrity(issues)
+        })?;
+    for Substitution {
+        node,
+        input,
+        old,
+        new,
+        ..
+    } in &substitutions
+    {
+        let Some(new) = new else { continue };
+        let input_path = input_paths.get(node).map_or_else(
+            || input.clone(),
+            |path| format!("{}/{input}", path.join("/")),
+        );
+        elogln!("-", :yellow "'{input_path}'", "now follows", :green ("'" (new.display(lock)) "'"), :dimmed ("(was '" (old.display(lock)) "')"));
+    }
+    if substitutions.is_empty() {
+        elogln!("-", :dimmed "every path is the shortest already");
+    }
+    Ok(substitutions)
+}
+
+fn prune_orphan_nodes(lock: &mut LockFile) -> Vec<(NodeId, Node)> {
+    elogln!(:bold :bright_magenta "Pruning orphaned nodes from modified lock.");
 
-struct FlakeNodeVisits<'a> {
-    inner: IndexMap<&'a str, u32>,
-    // Index of the node which this count is relative to.
-    root_index: &'a str,
+    let removed = allfollow::prune_orphan_nodes(lock);
+    for (id, node) in &removed {
+        let source = node.as_locked().map_or_else(
+            || lock.node_index(*id).to_owned(),
+            |node| node.original().to_string(),
+        );
+        elogln!("- removed", :red ("'" (lock.node_index(*id)) "'"), :dimmed "(" :dimmed (source) :dimmed ")");
+    }
+    removed
 }
 
-impl<'a> FlakeNodeVisits<'a> {
-    fn count_from_index<'new>(lock: &'new LockFile, index: &'new str) -> FlakeNodeVisits<'new> {
-        let mut node_hits = IndexMap::from_iter(lock.node_indices().zip(repeat(0_u32)));
-        recurse_inputs(lock, index.to_owned(), &mut |index| {
-            *node_hits.get_mut(index.as_str()).unwrap() += 1;
-        });
-        FlakeNodeVisits {
-            inner: node_hits,
-            root_index: index,
+fn log_explanation(before: &LockFile, after: &LockFile, explanation: &Explanation) {
+    let id = explanation.node;
+    logln!(
+        :bold (:bright_magenta "Explaining", :green ("'" (before.node_index(id)) "'")),
+        :dimmed "(" :dimmed (node_source(before, id)) :dimmed ")"
+    );
+
+    let reachable = after.input_paths();
+    logln!(:bold :bright_magenta "Referenced before pruning by:");
+    if explanation.references_before.is_empty() {
+        logln!("-", :dimmed "nothing, the node was unreachable already");
+    }
+    for reference in &explanation.references_before {
+        let path = reference.path.join("/");
+        match explanation.substitution_of(reference) {
+            Some(Substitution {
+                new: Some(new @ NodeEdge::Follows(_)),
+                reason,
+                ..
+            }) => {
+                logln!("-", :yellow "'{path}'", "now follows", :green ("'" (new.display(after)) "'"), :dimmed ("(" (reason) ")"));
+            }
+            Some(Substitution {
+                new: Some(new),
+                reason,
+                ..
+            }) => {
+                logln!("-", :yellow "'{path}'", "now references", :purple ("'" (new.display(after)) "'"), :dimmed ("(" (reason) ")"));
+            }
+            Some(Substitution {
+                new: None, reason, ..
+            }) => {
+                logln!("-", :yellow "'{path}'", "was not redirected", :dimmed ("(" (reason) ")"));
+            }
+            None if !reachable.contains_key(&reference.node) => {
+                logln!("-", :yellow "'{path}'", "was dropped along with", :purple ("'" (before.node_index(reference.node)) "'"), :dimmed "(unreachable after pruning)");
+            }
+            None if reference.node == before.root_id() => {
+                logln!("-", :yellow "'{path}'", "was left as it is", :dimmed "(a root input)");
+            }
+            None => {
+                logln!("-", :yellow "'{path}'", "was left as it is", :dimmed "(not considered for redirection)");
+            }
         }
     }
 
-    fn into_inner(self) -> IndexMap<&'a str, u32> {
-        self.inner
+    if explanation.removed {
+        logln!(
+            :bold (:red "Removed,"),
+            "because no input of a node reachable from the root references it after pruning."
+        );
+        return;
     }
-}
-
-impl<'a> From<FlakeNodeVisits<'a>> for IndexMap<&'a str, u32> {
-    fn from(value: FlakeNodeVisits<'a>) -> Self {
-        value.into_inner()
+    logln!(:bold (:bright_green "Kept,"), "because it is referenced after pruning by:");
+    for reference in &explanation.references_after {
+        let why = if reference.node == after.root_id() {
+            "a root input".to_owned()
+        } else {
+            match explanation.substitution_of(reference) {
+                Some(Substitution {
+                    new: None, reason, ..
+                }) => format!("not redirected: {reason}"),
+                Some(Substitution { reason, .. }) => format!("redirected here: {reason}"),
+                None => "not considered for redirection".to_owned(),
+            }
+        };
+        logln!("-", :yellow ("'" (reference.path.join("/")) "'"), :dimmed ("(" (why) ")"));
     }
 }
 
-impl<'a> std::ops::Deref for FlakeNodeVisits<'a> {
-    type Target = IndexMap<&'a str, u32>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.inner
-    }
+/// The URL of the node's `original` flake reference,
+/// or the node index itself if it has none (as is the case for the root).
+fn node_source(lock: &LockFile, id: NodeId) -> String {
+    lock.get_node(id)
+        .and_then(|node| node.as_locked().map(|node| node.original().to_string()))
+        .unwrap_or_else(|| lock.node_index(id).to_owned())
 }
 
-impl<'a> std::ops::DerefMut for FlakeNodeVisits<'a> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.inner
+fn edge_source(lock: &LockFile, edge: &NodeEdge) -> String {
+    match lock.resolve_edge(edge) {
+        Some(id) => node_source(lock, id),
+        None => edge.display(lock).to_string(),
     }
 }
 
-impl<'a> std::fmt::Display for FlakeNodeVisits<'a> {
+/// Colored display of the reference counts, one node per line.
+struct DisplayNodeVisits<'a, 'b>(&'b FlakeNodeVisits<'a>);
+
+impl std::fmt::Display for DisplayNodeVisits<'_, '_> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let max_pad = {
             let (mut min_len, mut max_len) = (0, 0);
-            for key in self.inner.keys() {
+            for key in self.0.keys() {
                 min_len = std::cmp::min(min_len, key.len());
                 max_len = std::cmp::max(max_len, key.len());
             }
             max_len - min_len
         };
-        for (index, count) in self.inner.iter() {
-            if index == &self.root_index {
+        for (index, count) in self.0.iter() {
+            let parents = self.0.parents()[index];
+            if *index == self.0.root_index() {
                 f.write_fmt(format_args_colored!(
                     :dimmed .("{:1$}", index, max_pad), :red "=", :dimmed &count;
                 ))?
             } else if *count <= 1 {
                 f.write_fmt(format_args_colored!(
-                    :bold :bright_yellow .("{:1$}", index, max_pad), :red "=", :dimmed &count;
+                    :bold :bright_yellow .("{:1$}", index, max_pad), :red "=", :dimmed &count,
+                    :dimmed .("({parents} parents)");
                 ))?
             } else {
                 f.write_fmt(format_args_colored!(
-                    .("{:1$}", index, max_pad), :red "=", :bold :bright_green &count;
+                    .("{:1$}", index, max_pad), :red "=", :bold :bright_green &count,
+                    :dimmed .("({parents} parents)");
                 ))?
             }
         }
@@ -561,96 +1085,82 @@ impl<'a> std::fmt::Display for FlakeNodeVisits<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use insta::assert_json_snapshot;
-    use std::fs;
-
-    use crate::{prune_orphan_nodes, read_flake_lock, substitute_flake_inputs_with_follows};
-
-    static HYPRLAND_LOCK_NO_FOLLOWS: &str = "samples/hyprland/no-follows/flake.lock";
 
-    #[test]
-    fn prune_hyprland_flake_lock() {
-        let mut lock = read_flake_lock(HYPRLAND_LOCK_NO_FOLLOWS.into());
-        substitute_flake_inputs_with_follows(&lock, false);
-        prune_orphan_nodes(&mut lock);
-        insta::with_settings!(
-            {
-                description => "Hyprland's `flake.lock` after substituting transitive inputs with follows.",
-                input_file => HYPRLAND_LOCK_NO_FOLLOWS,
-                omit_expression => true,
-                snapshot_path => "../tests/snapshots",
-            },
-            {
-                assert_json_snapshot!(&lock);
-            }
-        );
+    /// Run the command line `args` as `main` does, returning its exit code.
+    fn exit_code(args: &[&str]) -> i32 {
+        let command = parse_command_env_args().run_inner(args).unwrap();
+        run(command).map_or_else(|error| error.exit_code(), |()| 0)
     }
 
+    /// `check` fails with its own exit code on a lock which is not pruned,
+    /// and passes once the lock is pruned with the same options.
     #[test]
-    fn config_hyprland_flake_lock() {
-        use crate::print_flake_follows_config;
-        let lock = read_flake_lock(HYPRLAND_LOCK_NO_FOLLOWS.into());
-        let mut buf = Vec::new();
-        print_flake_follows_config(&lock, &mut buf);
-        let output = String::from_utf8(buf).unwrap();
-        insta::with_settings!(
-            {
-                description => "Generated config for Hyprland's `flake.lock`.",
-                input_file => HYPRLAND_LOCK_NO_FOLLOWS,
-                omit_expression => true,
-                snapshot_path => "../tests/snapshots",
-            },
-            {
-                insta::assert_snapshot!(output);
+    fn check_before_and_after_pruning() {
+        let dir = std::env::temp_dir().join("allfollow_test_check");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let samples = [
+            (
+                "samples/hyprland/no-follows/flake.lock",
+                Error::EXIT_NOT_PRUNED,
+            ),
+            (
+                "samples/hyprland/with-follows/flake.lock",
+                Error::EXIT_NOT_PRUNED,
+            ),
+            // The root `nixpkgs` already follows `nixpkgs-unstable` everywhere.
+            ("samples/nixpkgs-follows-nixpkgs-unstable/flake.lock", 0),
+        ];
+        let options: [&[&str]; 3] = [&[], &["--deep"], &["--deep", "--dedupe", "--hoist"]];
+        for (sample, unpruned) in samples {
+            for options in options {
+                let pruned = dir.join("flake.lock");
+                let pruned = pruned.to_str().unwrap();
+                let _ = std::fs::remove_file(pruned);
+                let with = |command, rest: &[_]| [&[command], options, rest].concat::<&str>();
+
+                assert_eq!(
+                    exit_code(&with("check", &[sample])),
+                    unpruned,
+                    "check {sample} with {options:?}"
+                );
+                assert_eq!(exit_code(&with("prune", &["-o", pruned, sample])), 0);
+                assert_eq!(
+                    exit_code(&with("check", &[pruned])),
+                    0,
+                    "check of {sample} pruned with {options:?}"
+                );
             }
-        );
+        }
     }
 
+    /// Warnings leave a lock valid, unless validating strictly.
     #[test]
-    fn config_in_place_test() {
-        let temp_dir = std::env::temp_dir().join("allfollow_test_in_place");
-        if temp_dir.exists() {
-            fs::remove_dir_all(&temp_dir).unwrap();
+    fn validate_with_warnings() {
+        let dir = std::env::temp_dir().join("allfollow_test_validate");
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
         }
-        fs::create_dir_all(&temp_dir).unwrap();
-
-        let lock_src = "samples/hyprland/no-follows/flake.lock";
-        let lock_dest = temp_dir.join("flake.lock");
-        fs::copy(lock_src, &lock_dest).unwrap();
-
-        let flake_nix_path = temp_dir.join("flake.nix");
-        let initial_content = r#"
-{
-  inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
-
-  # START INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY
-  # END INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY
-
-  outputs = { self, nixpkgs }: { };
-}
-"#;
-        fs::write(&flake_nix_path, initial_content).unwrap();
-
-        // Simulate CLI run
-        // We can't call main() directly easily because it parses env args.
-        // We can call the logic inside Config command.
-
-        let lock = read_flake_lock(Input::File(lock_dest.clone()));
-        let mut buf = Vec::new();
-        print_flake_follows_config(&lock, &mut buf);
-        let config_output = String::from_utf8(buf).unwrap();
-
-        update_flake_nix(&flake_nix_path, &config_output);
-
-        let updated_content = fs::read_to_string(&flake_nix_path).unwrap();
-
-        assert!(updated_content.contains("inputs = {"));
-        assert!(updated_content.contains("aquamarine.inputs.hyprutils.follows = \"hyprutils\";"));
-        assert!(updated_content.contains("# START INPUT FOLLOW BLOCK -- DO NOT EDIT MANUALLY"));
-
-        // Verify indentation (should be 2 spaces based on initial content)
-        // Note: print_flake_follows_config adds its own newlines, and we join with indentation.
-        // The block should start with 2 spaces.
-        assert!(updated_content.contains("\n  inputs = {"));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let sample = "samples/hyprland/no-follows/flake.lock";
+        let mut lock: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(sample).unwrap()).unwrap();
+        let orphan = lock["nodes"]["hyprlang"].clone();
+        lock["nodes"]["orphan"] = orphan;
+        let orphaned = dir.join("flake.lock");
+        std::fs::write(&orphaned, lock.to_string()).unwrap();
+        let orphaned = orphaned.to_str().unwrap();
+
+        assert_eq!(exit_code(&["validate", sample]), 0);
+        assert_eq!(exit_code(&["validate", "--strict", sample]), 0);
+        assert_eq!(exit_code(&["validate", orphaned]), 0);
+        assert_eq!(
+            exit_code(&["validate", "--strict", orphaned]),
+            Error::EXIT_INTEGRITY
+        );
     }
 }
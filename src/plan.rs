@@ -0,0 +1,479 @@
+//! A record of what pruning does to a lock, which can be reviewed
+//! before anything is written and then applied to the same lock.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::flake_lock::{LockFile, Node, NodeEdge, NodeId};
+use crate::prune::{Reason, Substitution};
+use crate::EXPECT_ROOT_EXIST;
+
+/// Every substitution and removal which pruning made to a lock, in order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct Plan {
+    /// The root inputs which were added by hoisting, see [`crate::hoist`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hoisted: Vec<HoistedInput>,
+    pub substitutions: Vec<PlannedSubstitution>,
+    pub removed: Vec<RemovedNode>,
+    /// The number of nodes before pruning, including the root.
+    pub nodes_before: usize,
+    /// The number of nodes after pruning, including the root.
+    pub nodes_after: usize,
+}
+
+/// A [`Substitution`], with nodes referenced by their index.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PlannedSubstitution {
+    /// The input path from the root to the input, as it was before pruning,
+    /// or the node index and the input name if the node was not reachable.
+    pub path: Vec<String>,
+    /// The index of the node which owns the input.
+    pub node: String,
+    pub input: String,
+    pub old: PlannedEdge,
+    /// The edge after substitution, or `None` if the input was left alone.
+    pub new: Option<PlannedEdge>,
+    /// Why the input was, or was not, redirected.
+    pub reason: Reason,
+}
+
+/// An edge as it is written in the lock file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PlannedEdge {
+    Indexed(String),
+    Follows(Vec<String>),
+}
+
+/// A root input which was added by hoisting.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoistedInput {
+    pub input: String,
+    /// The index of the node which the root input references.
+    pub node: String,
+}
+
+/// A node which pruning removed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RemovedNode {
+    pub node: String,
+    /// The URL of the node's `original` flake reference.
+    pub source: Option<String>,
+}
+
+/// Why a plan could not be read or applied to a lock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlanError {
+    /// The plan is not valid JSON, or does not have the shape of a plan.
+    Invalid(String),
+    /// The plan references a node which the lock does not have.
+    MissingNode(String),
+    /// The plan references an input which the node does not have.
+    MissingInput { node: String, input: String },
+    /// The plan adds a root input which the lock already has.
+    ExistingInput(String),
+    /// The input does not reference what the plan expects it to,
+    /// so the plan was made for a different lock.
+    Changed {
+        node: String,
+        input: String,
+        expected: PlannedEdge,
+        found: PlannedEdge,
+    },
+    /// A node which the plan removes is still referenced after the substitutions.
+    StillReferenced(String),
+    /// The lock does not have the number of nodes which the plan was made for.
+    DifferentLock { expected: usize, found: usize },
+    /// The lock does not have the number of nodes the plan expects.
+    NodeCount { expected: usize, found: usize },
+}
+
+impl Plan {
+    /// Record what happened between `before` and `after`, where `after` is a pruned
+    /// clone of `before`, by the `substitutions` and `removed` nodes which were made
+    /// to it in order.
+    pub fn new(
+        before: &LockFile,
+        after: &LockFile,
+        substitutions: &[Substitution],
+        removed: &[(NodeId, Node)],
+    ) -> Self {
+        let before_root = before.root().expect(EXPECT_ROOT_EXIST);
+        let hoisted = after
+            .root()
+            .expect(EXPECT_ROOT_EXIST)
+            .iter_edges()
+            .filter(|(input, _)| before_root.get_edge(input).is_none())
+            .filter_map(|(input, edge)| {
+                Some(HoistedInput {
+                    input: input.to_owned(),
+                    node: after.node_index(edge.id()?).to_owned(),
+                })
+            })
+            .collect();
+        let input_paths = before.input_paths();
+        let substitutions = substitutions
+            .iter()
+            .map(|substitution| {
+                let node = after.node_index(substitution.node).to_owned();
+                let path = match input_paths.get(&substitution.node) {
+                    Some(path) => {
+                        [path.as_slice(), std::slice::from_ref(&substitution.input)].concat()
+                    }
+                    None => vec![node.clone(), substitution.input.clone()],
+                };
+                PlannedSubstitution {
+                    path,
+                    node,
+                    input: substitution.input.clone(),
+                    old: PlannedEdge::new(after, &substitution.old),
+                    new: substitution
+                        .new
+                        .as_ref()
+                        .map(|edge| PlannedEdge::new(after, edge)),
+                    reason: substitution.reason.clone(),
+                }
+            })
+            .collect();
+        let removed = removed
+            .iter()
+            .map(|(id, node)| RemovedNode {
+                node: after.node_index(*id).to_owned(),
+                source: node.as_locked().map(|node| node.original().to_string()),
+            })
+            .collect();
+        Self {
+            hoisted,
+            substitutions,
+            removed,
+            nodes_before: before.node_ids().count(),
+            nodes_after: after.node_ids().count(),
+        }
+    }
+
+    pub fn from_reader(reader: impl std::io::Read) -> Result<Self> {
+        let deserializer = &mut serde_json::Deserializer::from_reader(reader);
+        serde_path_to_error::deserialize(deserializer).map_err(|error| {
+            if error.inner().is_io() {
+                Error::io("to read the plan", error.into_inner().into())
+            } else {
+                Error::Plan(PlanError::Invalid(error.to_string()))
+            }
+        })
+    }
+
+    /// Make exactly the hoists, substitutions and removals of the plan to the `lock`.
+    ///
+    /// The `lock` must have the number of nodes the plan was made for and every node
+    /// the plan references, every input must reference what the plan expects before
+    /// it is substituted, and every removed node must be unreachable afterward.
+    /// Otherwise the plan was made for a different lock, and the `lock` is left alone.
+    pub fn apply(&self, lock: &mut LockFile) -> Result<(), PlanError> {
+        let found = lock.node_ids().count();
+        if found != self.nodes_before {
+            return Err(PlanError::DifferentLock {
+                expected: self.nodes_before,
+                found,
+            });
+        }
+        let referenced = self
+            .hoisted
+            .iter()
+            .map(|hoisted| &hoisted.node)
+            .chain(self.substitutions.iter().flat_map(|substitution| {
+                let old = match &substitution.old {
+                    PlannedEdge::Indexed(index) => Some(index),
+                    PlannedEdge::Follows(_) => None,
+                };
+                std::iter::once(&substitution.node).chain(old)
+            }))
+            .chain(self.removed.iter().map(|removed| &removed.node));
+        for index in referenced {
+            if lock.find_node(index).is_none() {
+                return Err(PlanError::MissingNode(index.clone()));
+            }
+        }
+
+        let mut applied = lock.clone();
+        self.apply_checked(&mut applied)?;
+        *lock = applied;
+        Ok(())
+    }
+
+    fn apply_checked(&self, lock: &mut LockFile) -> Result<(), PlanError> {
+        for hoisted in &self.hoisted {
+            let id = lock
+                .find_node(&hoisted.node)
+                .ok_or_else(|| PlanError::MissingNode(hoisted.node.clone()))?;
+            let root = lock.get_node_mut(lock.root_id()).expect(EXPECT_ROOT_EXIST);
+            if root.get_edge(&hoisted.input).is_some() {
+                return Err(PlanError::ExistingInput(hoisted.input.clone()));
+            }
+            root.insert_edge(hoisted.input.clone(), NodeEdge::Indexed(id));
+        }
+        for substitution in &self.substitutions {
+            let Some(new) = &substitution.new else {
+                continue;
+            };
+            let new = new.to_edge(lock)?;
+            let id = lock
+                .find_node(&substitution.node)
+                .ok_or_else(|| PlanError::MissingNode(substitution.node.clone()))?;
+            let old = lock
+                .get_node(id)
+                .unwrap()
+                .get_edge(&substitution.input)
+                .ok_or_else(|| PlanError::MissingInput {
+                    node: substitution.node.clone(),
+                    input: substitution.input.clone(),
+                })?;
+            let found = PlannedEdge::new(lock, old);
+            if found != substitution.old {
+                return Err(PlanError::Changed {
+                    node: substitution.node.clone(),
+                    input: substitution.input.clone(),
+                    expected: substitution.old.clone(),
+                    found,
+                });
+            }
+            *lock
+                .get_node_mut(id)
+                .unwrap()
+                .get_edge_mut(&substitution.input)
+                .unwrap() = new;
+        }
+
+        let reachable = lock.input_paths().into_keys().collect::<HashSet<_>>();
+        let removed = self
+            .removed
+            .iter()
+            .map(|removed| {
+                let id = lock
+                    .find_node(&removed.node)
+                    .ok_or_else(|| PlanError::MissingNode(removed.node.clone()))?;
+                if reachable.contains(&id) {
+                    return Err(PlanError::StillReferenced(removed.node.clone()));
+                }
+                Ok(id)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for id in removed {
+            lock.remove_node(id);
+        }
+
+        let found = lock.node_ids().count();
+        if found != self.nodes_after {
+            return Err(PlanError::NodeCount {
+                expected: self.nodes_after,
+                found,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PlannedEdge {
+    pub fn new(lock: &LockFile, edge: &NodeEdge) -> Self {
+        match edge {
+            NodeEdge::Indexed(id) => Self::Indexed(lock.node_index(*id).to_owned()),
+            NodeEdge::Follows(path) => Self::Follows(path.clone()),
+        }
+    }
+
+    /// The edge in the `lock`, which must have the node if it is indexed.
+    pub fn to_edge(&self, lock: &LockFile) -> Result<NodeEdge, PlanError> {
+        match self {
+            Self::Indexed(index) => lock
+                .find_node(index)
+                .map(NodeEdge::Indexed)
+                .ok_or_else(|| PlanError::MissingNode(index.clone())),
+            Self::Follows(path) => Ok(NodeEdge::Follows(path.clone())),
+        }
+    }
+}
+
+impl fmt::Display for PlannedEdge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Indexed(index) => write!(f, "'{index}'"),
+            Self::Follows(path) => write!(f, "follows '{}'", path.join("/")),
+        }
+    }
+}
+
+/// The plan as human readable text, one substitution or removal per line.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.hoisted.is_empty() {
+            writeln!(f, "Hoisted root inputs:")?;
+            for hoisted in &self.hoisted {
+                writeln!(f, "- '{}': '{}'", hoisted.input, hoisted.node)?;
+            }
+        }
+        writeln!(f, "Substitutions:")?;
+        for substitution in &self.substitutions {
+            let path = substitution.path.join("/");
+            match &substitution.new {
+                Some(new) => writeln!(
+                    f,
+                    "- '{path}': {} -> {new} ({})",
+                    substitution.old, substitution.reason
+                )?,
+                None => writeln!(
+                    f,
+                    "- '{path}': keeps {} ({})",
+                    substitution.old, substitution.reason
+                )?,
+            }
+        }
+        writeln!(f, "Removed nodes:")?;
+        for removed in &self.removed {
+            match &removed.source {
+                Some(source) => writeln!(f, "- '{}' ({source})", removed.node)?,
+                None => writeln!(f, "- '{}'", removed.node)?,
+            }
+        }
+        writeln!(f, "Nodes: {} -> {}", self.nodes_before, self.nodes_after)
+    }
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(error) => write!(f, "failed to parse the plan: {error}"),
+            Self::MissingNode(node) => write!(f, "the lock has no node '{node}'"),
+            Self::MissingInput { node, input } => {
+                write!(f, "the node '{node}' has no input '{input}'")
+            }
+            Self::Changed {
+                node,
+                input,
+                expected,
+                found,
+            } => write!(
+                f,
+                "the input '{input}' of node '{node}' references {found}, \
+                but the plan expects {expected}"
+            ),
+            Self::ExistingInput(input) => {
+                write!(f, "the root already has an input '{input}' which the plan adds")
+            }
+            Self::StillReferenced(node) => {
+                write!(f, "the node '{node}' is still referenced after substitution")
+            }
+            Self::DifferentLock { expected, found } => write!(
+                f,
+                "the lock has {found} nodes, but the plan was made for a lock with {expected}"
+            ),
+            Self::NodeCount { expected, found } => write!(
+                f,
+                "the lock has {found} nodes after applying the plan, but the plan expects {expected}"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::prune::{
+        prune_orphan_nodes, substitute_flake_inputs_with_follows, SubstituteOptions,
+    };
+    use crate::resolve::{resolve_duplicate_sources, ResolvePolicy};
+    use crate::rules::Exclusion;
+    use crate::testing::*;
+
+    /// Applying the plan of pruning a lock to that lock prunes it the same way,
+    /// and it cannot be applied to a lock which was pruned already, or to another lock.
+    #[test]
+    fn plan_and_apply_hyprland_flake_lock() {
+        let original = read_sample(HYPRLAND_LOCK_NO_FOLLOWS);
+        let mut pruned = original.clone();
+        let options = SubstituteOptions {
+            depth: None,
+            ..Default::default()
+        };
+        let mut substitutions = resolve_duplicate_sources(&mut pruned, ResolvePolicy::Newest)
+            .into_iter()
+            .flat_map(|resolution| resolution.substitutions)
+            .collect::<Vec<_>>();
+        substitutions.extend(substitute_flake_inputs_with_follows(&mut pruned, &options));
+        let removed = prune_orphan_nodes(&mut pruned);
+        let plan = Plan::new(&original, &pruned, &substitutions, &removed);
+        assert_eq!(plan.nodes_before, original.node_ids().count());
+        assert_eq!(plan.nodes_after, pruned.node_ids().count());
+        assert_eq!(plan.removed.len(), removed.len());
+
+        let json = serde_json::to_string(&plan).unwrap();
+        let plan = Plan::from_reader(json.as_bytes()).unwrap();
+        let mut applied = original.clone();
+        plan.apply(&mut applied).unwrap();
+        assert_eq!(applied, pruned);
+
+        let mut applied = pruned.clone();
+        assert!(matches!(
+            plan.apply(&mut applied),
+            Err(PlanError::DifferentLock { .. })
+        ));
+
+        // The same number of nodes, but one the plan removes is under another index.
+        let mut other = original.clone();
+        let id = other.find_node(&plan.removed[0].node).unwrap();
+        let node = other.raw_node(id).unwrap();
+        other.remove_node(id);
+        other.insert_raw_nodes([(0, "renamed".to_owned(), node)]);
+        let unchanged = other.clone();
+        assert!(matches!(
+            plan.apply(&mut other),
+            Err(PlanError::MissingNode(_))
+        ));
+        assert_eq!(
+            other, unchanged,
+            "a failed plan should leave the lock alone"
+        );
+        let error = Plan::from_reader(&b"{\"substitutions\": 1}"[..]).unwrap_err();
+        assert_eq!(error.exit_code(), Error::EXIT_PLAN);
+    }
+
+    /// Reasons are tagged by their kind, with their fields beside it.
+    #[test]
+    fn reasons_are_tagged() {
+        let reasons = [
+            (Reason::SameName, json!({"kind": "sameName"})),
+            (
+                Reason::FollowedByRoot {
+                    root_input: "nixpkgs".to_owned(),
+                },
+                json!({"kind": "followedByRoot", "rootInput": "nixpkgs"}),
+            ),
+            (
+                Reason::Resolved {
+                    policy: ResolvePolicy::Newest,
+                },
+                json!({"kind": "resolved", "policy": "newest"}),
+            ),
+            (
+                Reason::Excluded(Exclusion::Excluded("hyprlang/**".parse().unwrap())),
+                json!({"kind": "excluded", "exclusion": "excluded", "pattern": "hyprlang/**"}),
+            ),
+            (
+                Reason::Excluded(Exclusion::Kept),
+                json!({"kind": "excluded", "exclusion": "kept"}),
+            ),
+        ];
+        for (reason, json) in reasons {
+            assert_eq!(serde_json::to_value(&reason).unwrap(), json);
+            assert_eq!(serde_json::from_value::<Reason>(json).unwrap(), reason);
+        }
+    }
+}
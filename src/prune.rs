@@ -0,0 +1,1273 @@
+//! Redirecting transitive inputs to the inputs of the root node,
+//! and removing the nodes which are no longer referenced afterward.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::count::FlakeNodeVisits;
+use crate::flake_lock::{FlakeRefType, LockFile, LockIssue, Node, NodeEdge, NodeId};
+use crate::resolve::ResolvePolicy;
+use crate::rules::{Exclusion, FollowRules};
+use crate::EXPECT_ROOT_EXIST;
+
+/// What happened to a single input of a node during substitution.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Substitution {
+    /// The node which owns the input.
+    pub node: NodeId,
+    /// The name of the input.
+    pub input: String,
+    /// The edge before substitution.
+    pub old: NodeEdge,
+    /// The edge after substitution,
+    /// or `None` if there was no suitable replacement.
+    pub new: Option<NodeEdge>,
+    /// Why the input was redirected, or why it was not.
+    pub reason: Reason,
+    /// How the node referenced by the input differs from the root input
+    /// it was redirected to, see [`SubstituteOptions::safety`].
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Why an input was, or was not, redirected.
+///
+/// Serialized as an object with the variant in camel case as its `kind`,
+/// and the fields of the variant beside it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Reason {
+    /// The root has an input of the same name.
+    SameName,
+    /// An alias of the rules names the root input to follow,
+    /// see [`crate::rules::FollowRules::target`].
+    Aliased {
+        /// The name of the root input which was aliased.
+        root_input: String,
+    },
+    /// The root input of a different name has the same `original` source,
+    /// see [`SubstituteOptions::match_source`].
+    SameSource {
+        /// The name of the root input which was matched.
+        root_input: String,
+    },
+    /// The node referenced by the input was identical to another,
+    /// see [`dedupe_identical_nodes`].
+    IdenticalContent,
+    /// The node referenced by the input lost to another of the same source,
+    /// see [`crate::resolve::resolve_duplicate_sources`].
+    Resolved { policy: ResolvePolicy },
+    /// The rules do not allow the input to be redirected,
+    /// see [`SubstituteOptions::rules`].
+    Excluded(Exclusion),
+    /// The node referenced by the input differs from the root input,
+    /// see [`Safety::Strict`] and [`Substitution::mismatches`].
+    Mismatched {
+        /// The name of the root input which was refused.
+        root_input: String,
+    },
+    /// The input already follows another input, which is left as it is,
+    /// see [`crate::rules::FollowRules::preserve_follows`].
+    Preserved {
+        /// The `follows` path of the input.
+        follows: Vec<String>,
+    },
+    /// The root input is a `follows` alias of this input, or of the node it
+    /// references, see [`crate::rules::FollowRules::is_followed_by_root`].
+    FollowedByRoot {
+        /// The name of the root input which follows the input.
+        root_input: String,
+    },
+    /// The `follows` path was rewritten to the shortest path to the same node,
+    /// see [`normalize_follows_paths`].
+    Normalized,
+    /// There was no suitable replacement.
+    Unmatched,
+}
+
+/// What to do when the node referenced by an input differs from the root input
+/// it would be redirected to, see [`Mismatch`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Safety {
+    /// Redirect the input without comparing the nodes.
+    #[default]
+    Off,
+    /// Redirect the input, but list the differences.
+    Warn,
+    /// Do not redirect the input, and list the differences.
+    Strict,
+}
+
+/// How the node referenced by an input differs from the root input
+/// it would be redirected to, comparing their `locked` references.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// The references are of different types, such as `github` and `git`.
+    Type {
+        old: FlakeRefType,
+        new: FlakeRefType,
+    },
+    /// The references are to different repositories,
+    /// see [`crate::flake_lock::FlakeRef::same_repository`].
+    Source { old: String, new: String },
+    /// The references are to different subdirectories.
+    Dir {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    /// One of the nodes is a flake and the other is not.
+    Flake { old: bool, new: bool },
+}
+
+/// How inputs are redirected by [`substitute_flake_inputs_with_follows`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubstituteOptions {
+    /// See [`substitute_node_inputs_with_root_inputs`].
+    pub indexed: bool,
+    /// How many levels below the root to redirect the inputs of nodes at,
+    /// or `None` to walk the whole graph.
+    /// The default of `Some(1)` only redirects the inputs of the root inputs.
+    pub depth: Option<usize>,
+    /// Redirect inputs which have no root input of the same name to a root input
+    /// with the same `original` source, see [`crate::flake_lock::FlakeRef::same_source`].
+    pub match_source: bool,
+    /// Which inputs may be redirected.
+    pub rules: FollowRules,
+    /// Whether to compare the node referenced by an input to the root input
+    /// before redirecting it.
+    pub safety: Safety,
+}
+
+impl Default for SubstituteOptions {
+    fn default() -> Self {
+        Self {
+            indexed: false,
+            depth: Some(1),
+            match_source: false,
+            rules: FollowRules::default(),
+            safety: Safety::Off,
+        }
+    }
+}
+
+impl Safety {
+    pub const ALL: [Self; 3] = [Self::Off, Self::Warn, Self::Strict];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Warn => "warn",
+            Self::Strict => "strict",
+        }
+    }
+}
+
+impl fmt::Display for Safety {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Safety {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|safety| safety.as_str() == s)
+            .ok_or_else(|| {
+                let names = Self::ALL.map(|safety| safety.as_str()).join(", ");
+                format!("unknown safety mode '{s}', expected one of: {names}")
+            })
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SameName => write!(f, "same name"),
+            Self::Aliased { root_input } => write!(f, "aliased to '{root_input}'"),
+            Self::SameSource { root_input } => write!(f, "same source as '{root_input}'"),
+            Self::IdenticalContent => write!(f, "identical content"),
+            Self::Resolved { policy } => write!(f, "resolved by the '{policy}' policy"),
+            Self::Excluded(exclusion) => write!(f, "{exclusion}"),
+            Self::Mismatched { root_input } => write!(f, "differs from '{root_input}'"),
+            Self::Preserved { follows } => write!(f, "already follows '{}'", follows.join("/")),
+            Self::FollowedByRoot { root_input } => write!(f, "followed by '{root_input}'"),
+            Self::Normalized => write!(f, "shortest path to the same node"),
+            Self::Unmatched => write!(f, "no suitable replacement"),
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn or_none(dir: &Option<String>) -> &str {
+            dir.as_deref().unwrap_or("none")
+        }
+        match self {
+            Self::Type { old, new } => write!(f, "type '{old}' differs from '{new}'"),
+            Self::Source { old, new } => write!(f, "source '{old}' differs from '{new}'"),
+            Self::Dir { old, new } => {
+                write!(f, "dir '{}' differs from '{}'", or_none(old), or_none(new))
+            }
+            Self::Flake { old, new } => write!(f, "flake = {old} differs from flake = {new}"),
+        }
+    }
+}
+
+/// Redirect the inputs of the root inputs to the root inputs of the same name,
+/// returning what happened to each of them in order.
+///
+/// Inputs without a replacement are walked breadth-first down to
+/// [`SubstituteOptions::depth`], so that the inputs of the nodes below them are
+/// redirected too. Inputs which are redirected are not walked any further,
+/// which makes the result equivalent to the nested `follows` declarations
+/// generated by [`crate::config::write_flake_follows_config`].
+/// Inputs which are preserved as they follow another input are not walked either.
+/// Each node is only visited once, at the shallowest depth it is reached,
+/// and the path it was first reached by is what [`SubstituteOptions::rules`]
+/// are matched against. Root inputs which are `follows` aliases are walked last,
+/// so the inputs of the node they lead to are only redirected under the alias
+/// if no other root input references the node.
+///
+/// Inputs follow the path given by [`FollowRules::follows_path`], which resolves
+/// root inputs that are aliases to what they follow, unless the rules say otherwise.
+pub fn substitute_flake_inputs_with_follows(
+    lock: &mut LockFile,
+    options: &SubstituteOptions,
+) -> Vec<Substitution> {
+    let root = lock.root().expect(EXPECT_ROOT_EXIST);
+    let within_depth = |depth: usize| options.depth.is_none_or(|max| depth <= max);
+    // Root inputs which are aliases come after the others, so that a node
+    // is reached by the name it is locked under rather than by an alias.
+    let (aliases, indexed) = root
+        .iter_edges()
+        .partition::<Vec<_>, _>(|(_, edge)| edge.path().is_some());
+    let mut queue = VecDeque::new();
+    let mut visited = HashSet::from([lock.root_id()]);
+    for (name, edge) in indexed.into_iter().chain(aliases) {
+        let Some(id) = lock.resolve_edge(edge).filter(|_| within_depth(1)) else {
+            continue;
+        };
+        if visited.insert(id) {
+            queue.push_back((id, vec![name.to_owned()]));
+        }
+    }
+
+    let mut substitutions = Vec::new();
+    while let Some((id, path)) = queue.pop_front() {
+        let node_substitutions = substitute_node_inputs_with_root_inputs(lock, id, &path, options);
+        if within_depth(path.len() + 1) {
+            for Substitution { input, old, .. } in node_substitutions
+                .iter()
+                .filter(|s| s.new.is_none() && !matches!(s.reason, Reason::Preserved { .. }))
+            {
+                if let Some(target) = lock.resolve_edge(old) {
+                    if visited.insert(target) {
+                        queue.push_back((
+                            target,
+                            [path.as_slice(), std::slice::from_ref(input)].concat(),
+                        ));
+                    }
+                }
+            }
+        }
+        substitutions.extend(node_substitutions);
+    }
+    substitutions
+}
+
+/// When `options.indexed == false`, the input replacements all will reference
+/// identically named inputs from the root node, or what those inputs are aliases of,
+/// see [`FollowRules::follows_path`]. This imitates input following behavior.
+///
+/// Otherwise, if `options.indexed == true`, the each input replacement will reference
+/// the node index that the root input resolves to.
+///
+/// With [`SubstituteOptions::match_source`], inputs without a root input
+/// of the same name are redirected to a root input with the same source instead.
+/// If several root inputs have the same source, the first which also
+/// has the same `ref` wins, otherwise the first of them.
+///
+/// The `path` of input names from the root to the node is what
+/// [`SubstituteOptions::rules`] are matched against, with the input name appended.
+/// An input which the rules alias to a root input of another name is redirected
+/// to that root input, or not at all if the root has no such input.
+///
+/// With [`crate::rules::FollowRules::preserve_follows`], inputs which already
+/// follow another input are left as they are, see [`Reason::Preserved`].
+///
+/// An input is never redirected to a root input which follows it,
+/// see [`Reason::FollowedByRoot`].
+///
+/// Unless [`SubstituteOptions::safety`] is off, the node which the input
+/// references is compared to the root input, see [`node_mismatches`].
+pub fn substitute_node_inputs_with_root_inputs(
+    lock: &mut LockFile,
+    id: NodeId,
+    path: &[String],
+    options: &SubstituteOptions,
+) -> Vec<Substitution> {
+    if id == lock.root_id() {
+        return Vec::new();
+    }
+    let node = lock.get_node(id).expect("a node to exist with this ID");
+    let edge_names = node
+        .iter_edges()
+        .map(|(name, _)| name.to_owned())
+        .collect::<Vec<_>>();
+    let mut substitutions = Vec::with_capacity(edge_names.len());
+    for edge_name in edge_names {
+        let root = lock.root().expect(EXPECT_ROOT_EXIST);
+        let input_path = [path, std::slice::from_ref(&edge_name)].concat();
+        let target = options.rules.target(&input_path);
+        let edge = lock.get_node(id).unwrap().get_edge(&edge_name).unwrap();
+        let matched = if let (true, Some(follows)) = (options.rules.preserve_follows, edge.path()) {
+            Err(Reason::Preserved {
+                follows: follows.clone(),
+            })
+        } else if let Err(exclusion) = options.rules.check(lock, id, &input_path) {
+            Err(Reason::Excluded(exclusion))
+        } else if target != edge_name {
+            match root.get_edge(target) {
+                Some(_) => Ok((
+                    target.to_owned(),
+                    Reason::Aliased {
+                        root_input: target.to_owned(),
+                    },
+                )),
+                None => Err(Reason::Unmatched),
+            }
+        } else if root.get_edge(&edge_name).is_some() {
+            Ok((edge_name.clone(), Reason::SameName))
+        } else if options.match_source {
+            same_source_root_input(lock, edge)
+                .map(|root_input| {
+                    let reason = Reason::SameSource {
+                        root_input: root_input.clone(),
+                    };
+                    (root_input, reason)
+                })
+                .ok_or(Reason::Unmatched)
+        } else {
+            Err(Reason::Unmatched)
+        };
+        let mut mismatches = Vec::new();
+        let (new, reason) = match matched {
+            Ok((root_input, _))
+                if options
+                    .rules
+                    .is_followed_by_root(lock, &root_input, id, &edge_name) =>
+            {
+                (None, Reason::FollowedByRoot { root_input })
+            }
+            Ok((root_input, reason)) => {
+                let root_edge = root.get_edge(&root_input).unwrap();
+                if options.safety != Safety::Off {
+                    let edge = lock.get_node(id).unwrap().get_edge(&edge_name).unwrap();
+                    if let (Some(old), Some(new)) =
+                        (lock.resolve_edge(edge), lock.resolve_edge(root_edge))
+                    {
+                        mismatches = node_mismatches(lock, old, new);
+                    }
+                }
+                if options.safety == Safety::Strict && !mismatches.is_empty() {
+                    (None, Reason::Mismatched { root_input })
+                } else if options.indexed {
+                    let new = lock
+                        .resolve_edge(root_edge)
+                        .map_or_else(|| root_edge.clone(), NodeEdge::Indexed);
+                    (Some(new), reason)
+                } else {
+                    let follows = options.rules.follows_path(lock, &root_input);
+                    (Some(NodeEdge::Follows(follows)), reason)
+                }
+            }
+            Err(reason) => (None, reason),
+        };
+        let edge = lock
+            .get_node_mut(id)
+            .unwrap()
+            .get_edge_mut(&edge_name)
+            .unwrap();
+        let old = match &new {
+            Some(new) => std::mem::replace(edge, new.clone()),
+            None => edge.clone(),
+        };
+        substitutions.push(Substitution {
+            node: id,
+            input: edge_name,
+            old,
+            new,
+            reason,
+            mismatches,
+        });
+    }
+    substitutions
+}
+
+/// How the node `old` differs from the node `new`, by the type, repository and
+/// subdirectory of their `locked` references and by their `flake` flags.
+/// Each difference is listed, so that one does not hide another.
+/// Nodes without references, such as the root, do not differ.
+pub fn node_mismatches(lock: &LockFile, old: NodeId, new: NodeId) -> Vec<Mismatch> {
+    let (Some(old), Some(new)) = (
+        lock.get_node(old).and_then(Node::as_locked),
+        lock.get_node(new).and_then(Node::as_locked),
+    ) else {
+        return Vec::new();
+    };
+    let (old_ref, new_ref) = (old.locked(), new.locked());
+    let mut mismatches = Vec::new();
+    if old_ref.r#type != new_ref.r#type {
+        mismatches.push(Mismatch::Type {
+            old: old_ref.r#type.clone(),
+            new: new_ref.r#type.clone(),
+        });
+    }
+    if old_ref.dir != new_ref.dir {
+        mismatches.push(Mismatch::Dir {
+            old: old_ref.dir.clone(),
+            new: new_ref.dir.clone(),
+        });
+    }
+    if !old_ref.same_repository(new_ref) {
+        mismatches.push(Mismatch::Source {
+            old: old.original().to_string(),
+            new: new.original().to_string(),
+        });
+    }
+    if old.is_flake() != new.is_flake() {
+        mismatches.push(Mismatch::Flake {
+            old: old.is_flake(),
+            new: new.is_flake(),
+        });
+    }
+    mismatches
+}
+
+// The name of the root input with the same source as the node `edge` references.
+fn same_source_root_input(lock: &LockFile, edge: &NodeEdge) -> Option<String> {
+    let target = lock.resolve_edge(edge)?;
+    let original = lock.get_node(target)?.as_locked()?.original();
+    let root = lock.root().expect(EXPECT_ROOT_EXIST);
+    let candidates = root
+        .iter_edges()
+        .filter_map(|(name, root_edge)| {
+            let root_original = lock
+                .get_node(lock.resolve_edge(root_edge)?)?
+                .as_locked()?
+                .original();
+            original
+                .same_source(root_original)
+                .then_some((name, root_original))
+        })
+        .collect::<Vec<_>>();
+    candidates
+        .iter()
+        .find(|(_, root_original)| root_original.r#ref == original.r#ref)
+        .or(candidates.first())
+        .map(|(name, _)| name.to_string())
+}
+
+/// Merge nodes which are reachable from the root and lock identical content,
+/// redirecting every edge which references a duplicate to the surviving node.
+///
+/// Nodes are identical when their `locked` attributes, their `flake` flag and
+/// the nodes which their inputs resolve to are all the same, so that the merged
+/// nodes would fetch and evaluate exactly the same thing. Merging nodes can make
+/// their parents identical, so this repeats until nothing more can be merged.
+///
+/// The survivor is the first of the identical nodes referenced by the root,
+/// otherwise the first in the order of the lock file.
+/// The duplicates are left orphaned, see [`prune_orphan_nodes`].
+pub fn dedupe_identical_nodes(lock: &mut LockFile) -> Vec<Substitution> {
+    let mut substitutions = Vec::new();
+    loop {
+        let survivors = identical_node_survivors(lock);
+        if survivors.is_empty() {
+            return substitutions;
+        }
+        let ids = lock.node_ids().collect::<Vec<_>>();
+        for id in ids {
+            let node = lock.get_node_mut(id).unwrap();
+            for (input, edge) in node.iter_edges_mut() {
+                let Some(&survivor) = edge.id().and_then(|target| survivors.get(&target)) else {
+                    continue;
+                };
+                let old = std::mem::replace(edge, NodeEdge::Indexed(survivor));
+                substitutions.push(Substitution {
+                    node: id,
+                    input: input.to_owned(),
+                    old,
+                    new: Some(NodeEdge::Indexed(survivor)),
+                    reason: Reason::IdenticalContent,
+                    mismatches: Vec::new(),
+                });
+            }
+        }
+    }
+}
+
+// Map each reachable duplicate node to the node which should replace it.
+fn identical_node_survivors(lock: &LockFile) -> HashMap<NodeId, NodeId> {
+    let node_hits = FlakeNodeVisits::count_from_node(lock, lock.root_id());
+    let root_inputs = lock
+        .edge_targets(lock.root_id())
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let mut groups: IndexMap<_, Vec<NodeId>> = IndexMap::new();
+    for id in lock.node_ids() {
+        if node_hits
+            .get(lock.node_index(id))
+            .is_none_or(|&count| count == 0)
+        {
+            continue;
+        }
+        let Some(node) = lock.get_node(id) else {
+            continue;
+        };
+        let Some(locked_node) = node.as_locked() else {
+            continue;
+        };
+        let locked =
+            serde_json::to_string(locked_node.locked()).expect("a flake reference to serialize");
+        let inputs = node
+            .iter_edges()
+            .map(|(name, edge)| (name.to_owned(), lock.resolve_edge(edge)))
+            .collect::<Vec<_>>();
+        groups
+            .entry((locked, locked_node.is_flake(), inputs))
+            .or_default()
+            .push(id);
+    }
+
+    let mut survivors = HashMap::new();
+    for group in groups.into_values().filter(|group| group.len() > 1) {
+        let survivor = group
+            .iter()
+            .copied()
+            .find(|id| root_inputs.contains(id))
+            .unwrap_or(group[0]);
+        survivors.extend(
+            group
+                .into_iter()
+                .filter(|&id| id != survivor)
+                .map(|id| (id, survivor)),
+        );
+    }
+    survivors
+}
+
+/// Remove every node which is not reachable from the root,
+/// returning the removed nodes in the order of the lock file.
+pub fn prune_orphan_nodes(lock: &mut LockFile) -> Vec<(NodeId, Node)> {
+    let node_hits = FlakeNodeVisits::count_from_node(lock, lock.root_id());
+
+    let dead_nodes = node_hits
+        .into_inner()
+        .into_iter()
+        .filter(|&(_, count)| count == 0)
+        .map(|(index, _)| lock.find_node(index).unwrap())
+        .collect::<Vec<_>>();
+
+    dead_nodes
+        .into_iter()
+        .map(|id| (id, lock.remove_node(id).unwrap()))
+        .collect()
+}
+
+/// Re-resolve every `follows` path of the `lock`, as it is after pruning, and rewrite
+/// those for which there is a shorter input path to the same node, returning the
+/// rewritten edges in order. The shorter paths pass through no `follows` edges,
+/// see [`LockFile::indexed_input_paths`], so that two paths are never rewritten
+/// to lead through each other. With [`FollowRules::preserve_follows`], the paths which
+/// are the same as in the lock `before` pruning are not rewritten.
+///
+/// Every path must resolve, and to the node it did `before` pruning, unless the input
+/// or an input which the path passes through was redirected by the `substitutions`.
+/// Every path must still resolve to the same node once all of them are rewritten,
+/// since rewriting one path can change where others lead through it.
+/// Otherwise the offending edges are returned as issues, and the `lock` is left alone.
+pub fn normalize_follows_paths(
+    lock: &mut LockFile,
+    before: &LockFile,
+    substitutions: &[Substitution],
+    rules: &FollowRules,
+) -> Result<Vec<Substitution>, Vec<LockIssue>> {
+    let input_paths = lock.input_paths();
+    let indexed_paths = lock.indexed_input_paths();
+    let follows_issue = |lock: &LockFile, id: NodeId, input: &str, follows: &[String]| {
+        (
+            lock.node_index(id).to_owned(),
+            input.to_owned(),
+            input_paths
+                .get(&id)
+                .map(|path| [path.as_slice(), &[input.to_owned()]].concat()),
+            follows.to_owned(),
+        )
+    };
+    let redirected = substitutions
+        .iter()
+        .filter(|s| s.new.is_some())
+        .map(|s| (s.node, s.input.clone()))
+        .collect::<HashSet<_>>();
+
+    let mut issues = Vec::new();
+    let mut targets = Vec::new();
+    for &id in input_paths.keys() {
+        for (input, edge) in lock.get_node(id).unwrap().iter_edges() {
+            let Some(follows) = edge.path() else {
+                continue;
+            };
+            // The shortest path to the target passes through no `follows` edge,
+            // so it is never the input itself, which would make it follow itself.
+            let resolved = lock.resolve_edge(edge);
+            let Some((target, shortest)) =
+                resolved.and_then(|target| Some((target, indexed_paths.get(&target)?)))
+            else {
+                let (node, input, input_path, follows) = follows_issue(lock, id, input, follows);
+                issues.push(LockIssue::UnresolvedFollows {
+                    node,
+                    input,
+                    input_path,
+                    follows,
+                });
+                continue;
+            };
+            let old_edge = before.get_node(id).and_then(|node| node.get_edge(input));
+            let expected = old_edge.and_then(|edge| before.resolve_edge(edge));
+            let mut passed = vec![(id, input.to_owned())];
+            lock.follow_path_inputs(follows, &mut passed);
+            if let Some(expected) = expected.filter(|&expected| {
+                expected != target && !passed.iter().any(|input| redirected.contains(input))
+            }) {
+                let (node, input, input_path, follows) = follows_issue(lock, id, input, follows);
+                issues.push(LockIssue::RetargetedFollows {
+                    node,
+                    input,
+                    input_path,
+                    follows,
+                    expected: before.node_index(expected).to_owned(),
+                    found: lock.node_index(target).to_owned(),
+                });
+                continue;
+            }
+            let preserved = rules.preserve_follows && old_edge == Some(edge);
+            let new = (!preserved && shortest.len() < follows.len())
+                .then(|| NodeEdge::Follows(shortest.clone()));
+            targets.push((id, input.to_owned(), target, new));
+        }
+    }
+    if !issues.is_empty() {
+        return Err(issues);
+    }
+
+    let mut normalized = lock.clone();
+    let mut substitutions = Vec::new();
+    for (id, input, _, new) in &targets {
+        let Some(new) = new else {
+            continue;
+        };
+        let edge = normalized
+            .get_node_mut(*id)
+            .unwrap()
+            .get_edge_mut(input)
+            .unwrap();
+        let old = std::mem::replace(edge, new.clone());
+        substitutions.push(Substitution {
+            node: *id,
+            input: input.clone(),
+            old,
+            new: Some(new.clone()),
+            reason: Reason::Normalized,
+            mismatches: Vec::new(),
+        });
+    }
+    for (id, input, expected, _) in targets {
+        let edge = normalized.get_node(id).unwrap().get_edge(&input).unwrap();
+        let found = normalized.resolve_edge(edge);
+        if found == Some(expected) {
+            continue;
+        }
+        let (node, input, input_path, follows) =
+            follows_issue(&normalized, id, &input, edge.path().unwrap());
+        issues.push(match found {
+            Some(found) => LockIssue::RetargetedFollows {
+                node,
+                input,
+                input_path,
+                follows,
+                expected: lock.node_index(expected).to_owned(),
+                found: normalized.node_index(found).to_owned(),
+            },
+            None => LockIssue::UnresolvedFollows {
+                node,
+                input,
+                input_path,
+                follows,
+            },
+        });
+    }
+    if !issues.is_empty() {
+        return Err(issues);
+    }
+    *lock = normalized;
+    Ok(substitutions)
+}
+
+#[cfg(test)]
+mod tests {
+    use insta::assert_json_snapshot;
+    use serde_json::json;
+
+    use super::*;
+    use crate::check::diff_locks;
+    use crate::hoist::hoist_shared_inputs;
+    use crate::resolve::resolve_duplicate_sources;
+    use crate::testing::*;
+
+    #[test]
+    fn prune_hyprland_flake_lock() {
+        let mut lock = read_sample(HYPRLAND_LOCK_NO_FOLLOWS);
+        prune(&mut lock, &SubstituteOptions::default());
+        insta::with_settings!(
+            {
+                description => "Hyprland's `flake.lock` after pruning it like `allfollow prune`.",
+                input_file => HYPRLAND_LOCK_NO_FOLLOWS,
+                omit_expression => true,
+                snapshot_path => "../tests/snapshots",
+            },
+            {
+                assert_json_snapshot!(&lock);
+            }
+        );
+    }
+
+    #[test]
+    fn prune_nixpkgs_follows_flake_lock() {
+        let original = read_sample(NIXPKGS_FOLLOWS_LOCK);
+        let mut lock = original.clone();
+        let substitutions =
+            substitute_flake_inputs_with_follows(&mut lock, &SubstituteOptions::default());
+        assert!(substitutions.is_empty());
+        assert!(prune_orphan_nodes(&mut lock).is_empty());
+        assert_eq!(lock, original);
+
+        let mut lock = read_nixpkgs_follows_sample_with_home_manager();
+        prune(&mut lock, &SubstituteOptions::default());
+        insta::with_settings!(
+            {
+                description => "The `nixpkgs-follows-nixpkgs-unstable` sample with a `home-manager` input, \
+                    after pruning it like `allfollow prune`.",
+                input_file => NIXPKGS_FOLLOWS_LOCK,
+                omit_expression => true,
+                snapshot_path => "../tests/snapshots",
+            },
+            {
+                assert_json_snapshot!(&lock);
+            }
+        );
+
+        let mut lock = read_nixpkgs_follows_sample_with_home_manager();
+        let options = SubstituteOptions {
+            rules: FollowRules {
+                root_aliases: crate::rules::RootAliases::Alias,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        substitute_flake_inputs_with_follows(&mut lock, &options);
+        let home_manager = lock.get_node(lock.find_node("home-manager").unwrap());
+        assert_eq!(
+            home_manager.unwrap().get_edge("nixpkgs"),
+            Some(&NodeEdge::from_iter(["nixpkgs"]))
+        );
+
+        let mut lock = read_nixpkgs_follows_sample_with_home_manager();
+        let options = SubstituteOptions {
+            indexed: true,
+            ..Default::default()
+        };
+        substitute_flake_inputs_with_follows(&mut lock, &options);
+        let home_manager = lock.get_node(lock.find_node("home-manager").unwrap());
+        let unstable = lock.find_node("nixpkgs-unstable").unwrap();
+        assert_eq!(
+            home_manager.unwrap().get_edge("nixpkgs"),
+            Some(&NodeEdge::Indexed(unstable))
+        );
+    }
+
+    #[test]
+    fn prune_is_idempotent() {
+        let prune = |lock: &mut LockFile, options: &SubstituteOptions, resolve: bool| {
+            let before = lock.clone();
+            let mut substitutions = Vec::new();
+            if resolve {
+                let resolutions = resolve_duplicate_sources(lock, ResolvePolicy::Newest);
+                substitutions.extend(resolutions.into_iter().flat_map(|r| r.substitutions));
+            }
+            hoist_shared_inputs(lock, options, 2);
+            substitutions.extend(substitute_flake_inputs_with_follows(lock, options));
+            substitutions.extend(dedupe_identical_nodes(lock));
+            prune_orphan_nodes(lock);
+            normalize_follows_paths(lock, &before, &substitutions, &options.rules).unwrap();
+        };
+        let samples = [
+            read_sample(HYPRLAND_LOCK_NO_FOLLOWS),
+            read_sample(HYPRLAND_LOCK_WITH_FOLLOWS),
+            read_nixpkgs_follows_sample_with_home_manager(),
+        ];
+        let options = [
+            SubstituteOptions::default(),
+            SubstituteOptions {
+                depth: None,
+                match_source: true,
+                ..Default::default()
+            },
+            SubstituteOptions {
+                indexed: true,
+                depth: None,
+                ..Default::default()
+            },
+        ];
+        for (sample, options) in samples
+            .iter()
+            .flat_map(|s| options.iter().map(move |o| (s, o)))
+        {
+            for resolve in [false, true] {
+                let mut once = sample.clone();
+                prune(&mut once, options, resolve);
+                let mut twice = once.clone();
+                prune(&mut twice, options, resolve);
+                assert_eq!(
+                    once, twice,
+                    "pruning twice with {options:?} changed the lock"
+                );
+                assert!(diff_locks(&once, &twice).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn prune_cyclic_flake_lock() {
+        let mut lock = lock_of(json!({
+            "a": github_node("a", json!({"a": ["a"], "b": "b", "nixpkgs": "nixpkgs_2"})),
+            "b": github_node("b", json!({"nixpkgs": "nixpkgs_2", "parent": ["a"]})),
+            "nixpkgs": {
+                "locked": {"owner": "NixOS", "repo": "nixpkgs", "rev": "x", "type": "github"},
+                "original": {"owner": "NixOS", "repo": "nixpkgs", "type": "github"}
+            },
+            "nixpkgs_2": {
+                "locked": {"owner": "NixOS", "repo": "nixpkgs", "rev": "y", "type": "github"},
+                "original": {"owner": "NixOS", "repo": "nixpkgs", "type": "github"}
+            },
+            "root": {"inputs": {"a": "a", "nixpkgs": "nixpkgs"}}
+        }));
+        let cycles = lock
+            .find_cycles()
+            .into_iter()
+            .map(|chain| chain.into_iter().map(|id| lock.node_index(id)).collect())
+            .collect::<Vec<Vec<_>>>();
+        assert_eq!(cycles, [["a", "a"].as_slice(), &["a", "b", "a"]]);
+
+        let node_hits = FlakeNodeVisits::count_from_node(&lock, lock.root_id());
+        assert_eq!(node_hits["nixpkgs_2"], 2);
+
+        substitute_flake_inputs_with_follows(&mut lock, &SubstituteOptions::default());
+        prune_orphan_nodes(&mut lock);
+        let node_hits = FlakeNodeVisits::count_from_node(&lock, lock.root_id());
+        assert_eq!(node_hits["nixpkgs"], 2);
+        assert_eq!(node_hits["nixpkgs_2"], 1);
+    }
+
+    #[test]
+    fn prune_nested_flake_lock() {
+        let nested = lock_of(json!({
+            "a": github_node("a", json!({"b": "b"})),
+            "b": github_node("b", json!({"nixpkgs": "nixpkgs_2"})),
+            "nixpkgs": {
+                "locked": {"owner": "NixOS", "repo": "nixpkgs", "rev": "x", "type": "github"},
+                "original": {"owner": "NixOS", "repo": "nixpkgs", "type": "github"}
+            },
+            "nixpkgs_2": {
+                "locked": {"owner": "NixOS", "repo": "nixpkgs", "rev": "y", "type": "github"},
+                "original": {"owner": "NixOS", "repo": "nixpkgs", "type": "github"}
+            },
+            "root": {"inputs": {"a": "a", "nixpkgs": "nixpkgs"}}
+        }));
+
+        let mut lock = nested.clone();
+        substitute_flake_inputs_with_follows(&mut lock, &SubstituteOptions::default());
+        prune_orphan_nodes(&mut lock);
+        assert!(lock.find_node("nixpkgs_2").is_some());
+
+        let mut lock = nested;
+        let options = SubstituteOptions {
+            depth: Some(2),
+            ..Default::default()
+        };
+        let substitutions = substitute_flake_inputs_with_follows(&mut lock, &options);
+        assert_eq!(substitutions.len(), 2);
+        prune_orphan_nodes(&mut lock);
+        assert!(lock.find_node("nixpkgs_2").is_none());
+        assert_eq!(
+            lock.follow_path(["a", "b", "nixpkgs"]),
+            lock.find_node("nixpkgs")
+        );
+    }
+
+    #[test]
+    fn dedupe_identical_flake_lock() {
+        let locked = |repo: &str| json!({"owner": "o", "repo": repo, "rev": "x", "narHash": "sha256-x", "type": "github"});
+        let original = |repo: &str| json!({"owner": "o", "repo": repo, "type": "github"});
+        let mut lock = lock_of(json!({
+            "a": {
+                "inputs": {"utils": "utils"},
+                "locked": locked("a"),
+                "original": original("a")
+            },
+            "a_2": {
+                "inputs": {"utils": "flake-utils"},
+                "locked": locked("a"),
+                "original": original("a")
+            },
+            "b": {
+                "inputs": {"a": "a_2"},
+                "locked": locked("b"),
+                "original": original("b")
+            },
+            "flake-utils": {"locked": locked("flake-utils"), "original": original("flake-utils")},
+            "utils": {"locked": locked("flake-utils"), "original": original("flake-utils")},
+            "utils_2": {
+                "flake": false,
+                "locked": locked("flake-utils"),
+                "original": original("flake-utils")
+            },
+            "root": {"inputs": {"a": "a", "b": "b", "flake-utils": "flake-utils", "raw": "utils_2"}}
+        }));
+
+        let substitutions = dedupe_identical_nodes(&mut lock);
+        prune_orphan_nodes(&mut lock);
+        let redirected = substitutions
+            .iter()
+            .map(|s| format!("{}/{}", lock.node_index(s.node), s.input))
+            .collect::<Vec<_>>();
+        assert_eq!(redirected, ["a/utils", "b/a"]);
+        let remaining = lock.node_indices().collect::<Vec<_>>();
+        assert_eq!(remaining, ["a", "b", "flake-utils", "root", "utils_2"]);
+    }
+
+    #[test]
+    fn prune_matching_source_flake_lock() {
+        let nixpkgs = |owner: &str, branch: &str| {
+            json!({
+                "locked": {"owner": owner, "repo": "nixpkgs", "rev": branch, "type": "github"},
+                "original": {"owner": owner, "ref": branch, "repo": "nixpkgs", "type": "github"}
+            })
+        };
+        let mut lock = lock_of(json!({
+            "a": github_node("a", json!({"pkgs": "nixpkgs_2", "stable-pkgs": "nixpkgs_3", "other": "other"})),
+            "nixpkgs": nixpkgs("NixOS", "nixos-unstable"),
+            "nixpkgs-stable": nixpkgs("NixOS", "nixos-24.05"),
+            "nixpkgs_2": nixpkgs("nixos", "nixpkgs-unstable"),
+            "nixpkgs_3": nixpkgs("NixOS", "nixos-24.05"),
+            "other": {
+                "locked": {"type": "git", "url": "https://example.com/other"},
+                "original": {"type": "git", "url": "https://example.com/other"}
+            },
+            "root": {"inputs": {"a": "a", "nixpkgs": "nixpkgs", "nixpkgs-stable": "nixpkgs-stable"}}
+        }));
+
+        let options = SubstituteOptions {
+            match_source: true,
+            ..Default::default()
+        };
+        let substitutions = substitute_flake_inputs_with_follows(&mut lock, &options);
+        let reasons = substitutions
+            .iter()
+            .map(|s| (s.input.as_str(), &s.reason))
+            .collect::<Vec<_>>();
+        let same_source = |root_input: &str| Reason::SameSource {
+            root_input: root_input.to_owned(),
+        };
+        assert_eq!(
+            reasons,
+            [
+                ("other", &Reason::Unmatched),
+                ("pkgs", &same_source("nixpkgs")),
+                ("stable-pkgs", &same_source("nixpkgs-stable")),
+            ]
+        );
+        prune_orphan_nodes(&mut lock);
+        assert!(lock.find_node("nixpkgs_2").is_none());
+        assert!(lock.find_node("nixpkgs_3").is_none());
+    }
+
+    #[test]
+    fn normalize_follows_paths_after_pruning() {
+        let mut lock = lock_of(json!({
+            "a": github_node("a", json!({"nixpkgs": "nixpkgs_2"})),
+            "b": github_node("b", json!({"pkgs": ["a", "nixpkgs"]})),
+            "c": github_node("c", json!({"b": "b", "pkgs": ["b", "pkgs"]})),
+            "nixpkgs": github_node("nixpkgs", json!({})),
+            "nixpkgs_2": github_node("nixpkgs", json!({})),
+            "root": {"inputs": {"a": "a", "b": "b", "c": "c", "nixpkgs": "nixpkgs"}}
+        }));
+
+        let before = lock.clone();
+        let substitutions = substitute_flake_inputs_with_follows(&mut lock, &Default::default());
+        prune_orphan_nodes(&mut lock);
+        let substitutions =
+            normalize_follows_paths(&mut lock, &before, &substitutions, &Default::default())
+                .unwrap();
+        let normalized = substitutions
+            .iter()
+            .map(|s| {
+                let path = |edge: &NodeEdge| edge.path().unwrap().join("/");
+                (
+                    format!("{}/{}", lock.node_index(s.node), s.input),
+                    path(&s.old),
+                    path(s.new.as_ref().unwrap()),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            normalized,
+            [
+                (
+                    "b/pkgs".to_owned(),
+                    "a/nixpkgs".to_owned(),
+                    "nixpkgs".to_owned()
+                ),
+                (
+                    "c/pkgs".to_owned(),
+                    "b/pkgs".to_owned(),
+                    "nixpkgs".to_owned()
+                ),
+            ]
+        );
+        let before = lock.clone();
+        assert!(
+            normalize_follows_paths(&mut lock, &before, &[], &Default::default())
+                .unwrap()
+                .is_empty()
+        );
+        assert!(lock.validate().is_empty());
+
+        let b = lock.find_node("b").unwrap();
+        *lock.get_node_mut(b).unwrap().get_edge_mut("pkgs").unwrap() =
+            NodeEdge::from_iter(["a", "missing"]);
+        let issues =
+            normalize_follows_paths(&mut lock, &before, &[], &Default::default()).unwrap_err();
+        assert!(matches!(
+            issues.as_slice(),
+            [LockIssue::UnresolvedFollows { node, .. }] if node == "b"
+        ));
+
+        // A path which leads elsewhere without any input along it being redirected.
+        let mut lock = lock_of(json!({
+            "a": github_node("a", json!({"dep": ["x"]})),
+            "x": github_node("x", json!({})),
+            "y": github_node("y", json!({})),
+            "root": {"inputs": {"a": "a", "x": "x", "y": "y"}}
+        }));
+        let before = lock.clone();
+        let (x, y) = (lock.find_node("x").unwrap(), lock.find_node("y").unwrap());
+        let root = lock.get_node_mut(lock.root_id()).unwrap();
+        *root.get_edge_mut("x").unwrap() = NodeEdge::Indexed(y);
+        let issues =
+            normalize_follows_paths(&mut lock, &before, &[], &Default::default()).unwrap_err();
+        assert!(matches!(
+            issues.as_slice(),
+            [LockIssue::RetargetedFollows { node, expected, found, .. }]
+                if node == "a" && expected == "x" && found == "y"
+        ));
+        let redirected = Substitution {
+            node: lock.root_id(),
+            input: "x".to_owned(),
+            old: NodeEdge::Indexed(x),
+            new: Some(NodeEdge::Indexed(y)),
+            reason: Reason::IdenticalContent,
+            mismatches: Vec::new(),
+        };
+        assert!(
+            normalize_follows_paths(&mut lock, &before, &[redirected], &Default::default()).is_ok()
+        );
+    }
+
+    /// Two `follows` paths which are shorter through each other than through
+    /// the inputs they lead to are not rewritten to follow each other.
+    #[test]
+    fn normalize_follows_paths_through_each_other() {
+        let original = lock_of(json!({
+            "a": github_node("a", json!({"b": "b"})),
+            "b": github_node("b", json!({"c": "c"})),
+            "c": github_node("c", json!({})),
+            "k": github_node("k", json!({"q": ["m", "p"]})),
+            "m": github_node("m", json!({"p": ["a", "b", "c"]})),
+            "root": {"inputs": {"a": "a", "k": "k", "m": "m"}}
+        }));
+        assert!(original.validate().is_empty());
+
+        let mut lock = original.clone();
+        let normalized =
+            normalize_follows_paths(&mut lock, &original, &[], &Default::default()).unwrap();
+        assert!(normalized.is_empty());
+        assert_eq!(lock, original);
+
+        let mut lock = original.clone();
+        prune(&mut lock, &SubstituteOptions::default());
+        assert!(lock.validate().is_empty());
+        let c = lock.find_node("c");
+        assert_eq!(lock.follow_path(["k", "q"]), c);
+        assert_eq!(lock.follow_path(["m", "p"]), c);
+        assert!(diff_locks(&original, &lock).is_empty());
+    }
+
+    #[test]
+    fn normalize_follows_paths_preserve_follows() {
+        let original = read_sample(HYPRLAND_LOCK_WITH_FOLLOWS);
+        let options = SubstituteOptions {
+            rules: FollowRules {
+                preserve_follows: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut lock = original.clone();
+        let substitutions = substitute_flake_inputs_with_follows(&mut lock, &options);
+        prune_orphan_nodes(&mut lock);
+        let normalized =
+            normalize_follows_paths(&mut lock, &original, &substitutions, &options.rules).unwrap();
+        assert!(normalized.is_empty());
+        let gitignore = lock.follow_path(["pre-commit-hooks", "gitignore"]).unwrap();
+        let edge = lock
+            .get_node(gitignore)
+            .unwrap()
+            .get_edge("nixpkgs")
+            .unwrap();
+        assert_eq!(edge, &NodeEdge::from_iter(["pre-commit-hooks", "nixpkgs"]));
+
+        // Without preserving them, the upstream follows are shortened.
+        let mut lock = original.clone();
+        let substitutions = substitute_flake_inputs_with_follows(&mut lock, &Default::default());
+        prune_orphan_nodes(&mut lock);
+        let normalized =
+            normalize_follows_paths(&mut lock, &original, &substitutions, &Default::default())
+                .unwrap();
+        assert!(!normalized.is_empty());
+    }
+
+    #[test]
+    fn prune_safety_flake_lock() {
+        let sample = lock_of(json!({
+            "a": github_node("a", json!({"data": "data_2", "nixpkgs": "nixpkgs_2", "systems": "systems_2"})),
+            "data": {
+                "flake": false,
+                "locked": {"owner": "o", "repo": "data", "type": "github"},
+                "original": {"owner": "o", "repo": "data", "type": "github"}
+            },
+            "data_2": github_node("data", json!({})),
+            "nixpkgs": {
+                "locked": {"owner": "NixOS", "repo": "nixpkgs", "rev": "x", "type": "github"},
+                "original": {"owner": "NixOS", "repo": "nixpkgs", "type": "github"}
+            },
+            "nixpkgs_2": {
+                "locked": {"owner": "nixos", "repo": "nixpkgs", "rev": "y", "type": "github"},
+                "original": {"id": "nixpkgs", "type": "indirect"}
+            },
+            "systems": {
+                "locked": {"owner": "nix-systems", "repo": "default", "type": "github"},
+                "original": {"owner": "nix-systems", "repo": "default", "type": "github"}
+            },
+            "systems_2": {
+                "locked": {"owner": "nix-systems", "repo": "x86_64-linux", "type": "github"},
+                "original": {"owner": "nix-systems", "repo": "x86_64-linux", "type": "github"}
+            },
+            "root": {"inputs": {"a": "a", "data": "data", "nixpkgs": "nixpkgs", "systems": "systems"}}
+        }));
+
+        let substitute = |safety: Safety| {
+            let mut lock = sample.clone();
+            let options = SubstituteOptions {
+                safety,
+                ..Default::default()
+            };
+            let substitutions = substitute_flake_inputs_with_follows(&mut lock, &options);
+            substitutions
+                .into_iter()
+                .map(|s| (s.input, s.new.is_some(), s.mismatches))
+                .collect::<Vec<_>>()
+        };
+        let data_mismatch = Mismatch::Flake {
+            old: true,
+            new: false,
+        };
+        let systems_mismatch = Mismatch::Source {
+            old: "github:nix-systems/x86_64-linux".to_owned(),
+            new: "github:nix-systems/default".to_owned(),
+        };
+        assert_eq!(
+            substitute(Safety::Warn),
+            [
+                ("data".to_owned(), true, vec![data_mismatch.clone()]),
+                ("nixpkgs".to_owned(), true, vec![]),
+                ("systems".to_owned(), true, vec![systems_mismatch.clone()]),
+            ]
+        );
+        assert_eq!(
+            substitute(Safety::Strict),
+            [
+                ("data".to_owned(), false, vec![data_mismatch]),
+                ("nixpkgs".to_owned(), true, vec![]),
+                ("systems".to_owned(), false, vec![systems_mismatch]),
+            ]
+        );
+        assert!(substitute(Safety::Off)
+            .iter()
+            .all(|(_, redirected, mismatches)| *redirected && mismatches.is_empty()));
+    }
+
+    /// A different subdirectory does not hide a different repository.
+    #[test]
+    fn node_mismatches_are_independent() {
+        let mut moved = github_node("moved", json!({}));
+        moved["locked"]["dir"] = "sub".into();
+        let lock = lock_of(json!({
+            "a": github_node("a", json!({"b": "moved"})),
+            "b": github_node("b", json!({})),
+            "moved": moved,
+            "root": {"inputs": {"a": "a", "b": "b"}}
+        }));
+        let (old, new) = (
+            lock.find_node("moved").unwrap(),
+            lock.find_node("b").unwrap(),
+        );
+
+        assert_eq!(
+            node_mismatches(&lock, old, new),
+            [
+                Mismatch::Dir {
+                    old: Some("sub".to_owned()),
+                    new: None,
+                },
+                Mismatch::Source {
+                    old: "github:o/moved".to_owned(),
+                    new: "github:o/b".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(node_mismatches(&lock, new, new), []);
+    }
+}
@@ -0,0 +1,318 @@
+//! Unifying nodes which lock different revisions of the same source,
+//! by a policy which picks the revision that every other one is replaced with.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::count::FlakeNodeVisits;
+use crate::flake_lock::{FlakeRef, LockFile, NodeEdge, NodeId};
+use crate::prune::{Reason, Substitution};
+
+/// How to pick the winner among nodes of the same source.
+/// With any policy, ties between equal revisions go to the root input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolvePolicy {
+    /// The revision with the latest `lastModified`, or `revCount`.
+    Newest,
+    /// The revision with the earliest `lastModified`, or `revCount`.
+    Oldest,
+    /// The revision of the root input, which is what following it would do.
+    Root,
+    /// The revision referenced by the most inputs, ties going to the newest.
+    Majority,
+}
+
+/// Which node won among nodes of the same source, and why.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolution {
+    pub policy: ResolvePolicy,
+    /// The node which every edge to the losers now references.
+    pub winner: NodeId,
+    /// The other nodes of the same source, which are left orphaned.
+    pub losers: Vec<NodeId>,
+    /// A human readable explanation of why the winner was chosen.
+    pub why: String,
+    /// The edges which were retargeted from the losers to the winner.
+    pub substitutions: Vec<Substitution>,
+}
+
+impl ResolvePolicy {
+    pub const ALL: [Self; 4] = [Self::Newest, Self::Oldest, Self::Root, Self::Majority];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Newest => "newest",
+            Self::Oldest => "oldest",
+            Self::Root => "root",
+            Self::Majority => "majority",
+        }
+    }
+}
+
+impl fmt::Display for ResolvePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ResolvePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| {
+                let names = Self::ALL.map(|policy| policy.as_str()).join(", ");
+                format!("unknown resolve policy '{s}', expected one of: {names}")
+            })
+    }
+}
+
+// A node which is reachable from the root, with what the policies compare.
+struct Candidate {
+    id: NodeId,
+    is_root_input: bool,
+    references: usize,
+    age: Option<(u64, u64)>,
+}
+
+/// Group the nodes which are reachable from the root by their `original` source
+/// (see [`FlakeRef::same_source`]) and `ref`, pick a winner in each group with more
+/// than one revision, and retarget every edge which references the other nodes to it.
+/// Nodes of different branches or tags, such as `nixos-24.05` and `nixos-unstable`,
+/// are never unified, as one of them was most likely pinned on purpose.
+/// This includes the edges of the root, so the winner may be a transitive node.
+///
+/// Groups are left alone when the policy cannot decide between them:
+/// when several root inputs share the source (they were pinned separately on purpose),
+/// when [`ResolvePolicy::Root`] finds no root input among them, or when
+/// [`ResolvePolicy::Newest`] and [`ResolvePolicy::Oldest`] find a node which locks
+/// neither `lastModified` nor `revCount`.
+pub fn resolve_duplicate_sources(lock: &mut LockFile, policy: ResolvePolicy) -> Vec<Resolution> {
+    let mut resolutions = Vec::new();
+    for group in same_source_groups(lock) {
+        let Some((winner, why)) = pick_winner(lock, &group, policy) else {
+            continue;
+        };
+        let losers = group
+            .iter()
+            .map(|candidate| candidate.id)
+            .filter(|&id| id != winner)
+            .collect::<Vec<_>>();
+        let substitutions = retarget_edges(lock, &losers, winner, policy);
+        resolutions.push(Resolution {
+            policy,
+            winner,
+            losers,
+            why,
+            substitutions,
+        });
+    }
+    resolutions
+}
+
+fn same_source_groups(lock: &LockFile) -> Vec<Vec<Candidate>> {
+    let node_hits = FlakeNodeVisits::count_from_node(lock, lock.root_id());
+    let reachable = lock
+        .node_ids()
+        .filter(|&id| {
+            node_hits
+                .get(lock.node_index(id))
+                .is_some_and(|&count| count > 0)
+        })
+        .collect::<Vec<_>>();
+    let root_inputs = lock.edge_targets(lock.root_id());
+
+    let mut references = HashMap::<NodeId, usize>::new();
+    for &id in &reachable {
+        for target in lock.edge_targets(id) {
+            *references.entry(target).or_default() += 1;
+        }
+    }
+
+    let mut groups: Vec<(&FlakeRef, Vec<Candidate>)> = Vec::new();
+    for id in reachable {
+        let Some(node) = lock.get_node(id).and_then(|node| node.as_locked()) else {
+            continue;
+        };
+        let locked = node.locked();
+        let candidate = Candidate {
+            id,
+            is_root_input: root_inputs.contains(&id),
+            references: references.get(&id).copied().unwrap_or(0),
+            age: match (locked.last_modified, locked.rev_count) {
+                (None, None) => None,
+                (last_modified, rev_count) => {
+                    Some((last_modified.unwrap_or(0), rev_count.unwrap_or(0)))
+                }
+            },
+        };
+        match groups.iter_mut().find(|(original, _)| {
+            original.same_source(node.original()) && original.r#ref == node.original().r#ref
+        }) {
+            Some((_, group)) => group.push(candidate),
+            None => groups.push((node.original(), vec![candidate])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(_, group)| group)
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+fn pick_winner(
+    lock: &LockFile,
+    group: &[Candidate],
+    policy: ResolvePolicy,
+) -> Option<(NodeId, String)> {
+    let root_inputs = group.iter().filter(|c| c.is_root_input).count();
+    if root_inputs > 1 {
+        return None;
+    }
+    let nodes = group.len();
+    let describe_age = |candidate: &Candidate| {
+        let locked = lock
+            .get_node(candidate.id)
+            .and_then(|node| node.as_locked())
+            .map(|node| node.locked());
+        match locked {
+            Some(FlakeRef {
+                last_modified: Some(last_modified),
+                ..
+            }) => format!("lastModified {last_modified}"),
+            Some(FlakeRef {
+                rev_count: Some(rev_count),
+                ..
+            }) => format!("revCount {rev_count}"),
+            _ => "no revision date".to_owned(),
+        }
+    };
+    // Equal revisions go to the root input, so that its edge stays as it is.
+    // `max_by_key` and `min_by_key` pick the last and first of equals,
+    // so reverse for the maximum to prefer the first node in the lock.
+    let winner = match policy {
+        ResolvePolicy::Newest => {
+            if group.iter().any(|c| c.age.is_none()) {
+                return None;
+            }
+            let winner = group
+                .iter()
+                .rev()
+                .max_by_key(|c| (c.age, c.is_root_input))?;
+            let why = format!("newest of {nodes} nodes ({})", describe_age(winner));
+            (winner, why)
+        }
+        ResolvePolicy::Oldest => {
+            if group.iter().any(|c| c.age.is_none()) {
+                return None;
+            }
+            let winner = group.iter().min_by_key(|c| (c.age, !c.is_root_input))?;
+            let why = format!("oldest of {nodes} nodes ({})", describe_age(winner));
+            (winner, why)
+        }
+        ResolvePolicy::Root => {
+            let winner = group.iter().find(|c| c.is_root_input)?;
+            let why = format!("pinned by the root, among {nodes} nodes");
+            (winner, why)
+        }
+        ResolvePolicy::Majority => {
+            let winner = group
+                .iter()
+                .rev()
+                .max_by_key(|c| (c.references, c.age, c.is_root_input))?;
+            let why = format!(
+                "referenced by {} of {} inputs, among {nodes} nodes",
+                winner.references,
+                group.iter().map(|c| c.references).sum::<usize>()
+            );
+            (winner, why)
+        }
+    };
+    Some((winner.0.id, winner.1))
+}
+
+fn retarget_edges(
+    lock: &mut LockFile,
+    losers: &[NodeId],
+    winner: NodeId,
+    policy: ResolvePolicy,
+) -> Vec<Substitution> {
+    let mut substitutions = Vec::new();
+    let ids = lock.node_ids().collect::<Vec<_>>();
+    for id in ids {
+        let node = lock.get_node_mut(id).unwrap();
+        for (input, edge) in node.iter_edges_mut() {
+            if !edge.id().is_some_and(|target| losers.contains(&target)) {
+                continue;
+            }
+            let old = std::mem::replace(edge, NodeEdge::Indexed(winner));
+            substitutions.push(Substitution {
+                node: id,
+                input: input.to_owned(),
+                old,
+                new: Some(NodeEdge::Indexed(winner)),
+                reason: Reason::Resolved { policy },
+                mismatches: Vec::new(),
+            });
+        }
+    }
+    substitutions
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::prune::prune_orphan_nodes;
+    use crate::testing::*;
+
+    #[test]
+    fn resolve_duplicate_sources_flake_lock() {
+        let node = |inputs: serde_json::Value, repo: &str, rev: &str, last_modified: u64| {
+            let mut node = github_node(repo, inputs);
+            node["locked"]["rev"] = rev.into();
+            node["locked"]["lastModified"] = last_modified.into();
+            node
+        };
+        let sample = lock_of(json!({
+            "a": node(json!({"lib": "lib_2"}), "a", "a", 1),
+            "b": node(json!({"lib": "lib_2"}), "b", "b", 1),
+            "c": node(json!({"lib": "lib_3"}), "c", "c", 1),
+            "lib": node(json!({}), "lib", "x", 20),
+            "lib_2": node(json!({}), "lib", "y", 10),
+            "lib_3": node(json!({}), "lib", "z", 30),
+            "root": {"inputs": {"a": "a", "b": "b", "c": "c", "lib": "lib"}}
+        }));
+
+        let winners = ResolvePolicy::ALL.map(|policy| {
+            let mut lock = sample.clone();
+            let resolutions = resolve_duplicate_sources(&mut lock, policy);
+            assert_eq!(resolutions.len(), 1);
+            prune_orphan_nodes(&mut lock);
+            assert_eq!(lock.follow_path(["a", "lib"]), lock.follow_path(["lib"]));
+            assert_eq!(lock.follow_path(["c", "lib"]), lock.follow_path(["lib"]));
+            sample.node_index(resolutions[0].winner).to_owned()
+        });
+        assert_eq!(winners, ["lib_3", "lib_2", "lib", "lib_2"]);
+
+        // A release pinned by one input is not unified with another branch.
+        let mut lock = serde_json::to_value(&sample).unwrap();
+        lock["nodes"]["lib_3"]["original"]["ref"] = "release-1".into();
+        let mut lock: LockFile = serde_json::from_value(lock).unwrap();
+        let resolutions = resolve_duplicate_sources(&mut lock, ResolvePolicy::Newest);
+        assert_eq!(resolutions.len(), 1);
+        assert_eq!(lock.node_index(resolutions[0].winner), "lib");
+        assert_eq!(lock.follow_path(["c", "lib"]), lock.find_node("lib_3"));
+
+        assert_eq!("root".parse(), Ok(ResolvePolicy::Root));
+        assert!("latest".parse::<ResolvePolicy>().is_err());
+    }
+}
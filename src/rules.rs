@@ -0,0 +1,391 @@
+//! Rules deciding which inputs are redirected to the root inputs,
+//! and to which of them, shared by pruning and the generated `follows` config.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::flake_lock::{LockFile, NodeEdge, NodeId};
+use crate::EXPECT_ROOT_EXIST;
+
+/// A glob pattern over input paths, such as `hyprland/nixpkgs`.
+///
+/// Segments are separated by `/`, a `*` matches any part of a single segment,
+/// and a segment of `**` matches any number of segments.
+/// A pattern without a `/` only has to match the last segment of a path,
+/// so `nixpkgs` matches the `nixpkgs` input of every node.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct InputPattern {
+    source: String,
+    segments: Vec<String>,
+}
+
+/// Redirect inputs matching a pattern to the root input named `target`,
+/// written as `nixpkgs-lib=nixpkgs` or `hyprland/nixpkgs=nixpkgs-unstable`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputAlias {
+    pub pattern: InputPattern,
+    pub target: String,
+}
+
+/// Which inputs may be redirected, and to which root inputs.
+///
+/// In the project settings, the aliases are a table of patterns to targets,
+/// see [`crate::settings::Settings::rules`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FollowRules {
+    /// Inputs matching any of these are never redirected.
+    pub exclude: Vec<InputPattern>,
+    /// If not empty, only inputs matching any of these are redirected.
+    pub only: Vec<InputPattern>,
+    /// Indices of the nodes whose inputs are never redirected.
+    pub keep: Vec<String>,
+    /// Root inputs to redirect inputs to instead of the root input of the same name,
+    /// see [`FollowRules::target`].
+    #[serde(deserialize_with = "deserialize_aliases")]
+    pub aliases: Vec<InputAlias>,
+    /// Leave inputs which already follow another input as they are,
+    /// such as an upstream `nixpkgs` following `nixpkgs-unstable`,
+    /// and only redirect inputs which reference a node index.
+    pub preserve_follows: bool,
+    /// What inputs follow when the root input of their name is itself an alias.
+    pub root_aliases: RootAliases,
+}
+
+/// What an input follows when the root input it follows is itself a `follows` alias
+/// of another input, such as a root `nixpkgs` which follows `nixpkgs-unstable`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootAliases {
+    /// Follow the path which the alias ultimately follows,
+    /// so that no chain of `follows` is left to resolve.
+    #[default]
+    Target,
+    /// Follow the alias itself, as writing its name in the `flake.nix` would.
+    Alias,
+}
+
+/// Why the rules do not allow an input to be redirected.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "exclusion", content = "pattern", rename_all = "camelCase")]
+pub enum Exclusion {
+    /// The input path matches a pattern of [`FollowRules::exclude`].
+    Excluded(InputPattern),
+    /// The input path does not match any pattern of [`FollowRules::only`].
+    NotOnly,
+    /// The node which has the input is in [`FollowRules::keep`].
+    Kept,
+}
+
+impl RootAliases {
+    pub const ALL: [Self; 2] = [Self::Target, Self::Alias];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Target => "target",
+            Self::Alias => "alias",
+        }
+    }
+}
+
+impl fmt::Display for RootAliases {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for RootAliases {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| {
+                let names = Self::ALL.map(|mode| mode.as_str()).join(", ");
+                format!("unknown root aliases mode '{s}', expected one of: {names}")
+            })
+    }
+}
+
+impl InputPattern {
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the pattern was written with a `/`, scoping it to certain paths.
+    pub fn is_scoped(&self) -> bool {
+        self.source.contains('/')
+    }
+
+    /// Whether the path of input names from the root matches the pattern.
+    pub fn matches(&self, path: &[impl AsRef<str>]) -> bool {
+        let path = path.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+        match_segments(&self.segments, &path)
+    }
+}
+
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_segments(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path)) => match_glob(first, segment) && match_segments(rest, path),
+            None => false,
+        },
+    }
+}
+
+// Match a single segment, where `*` matches any run of characters.
+fn match_glob(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .any(|i| match_glob(rest, &text[i..]))
+        }
+    }
+}
+
+impl FromStr for InputPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = s.split('/').map(str::to_owned).collect::<Vec<_>>();
+        if segments.iter().any(String::is_empty) {
+            return Err(format!("the input pattern '{s}' has an empty segment"));
+        }
+        if segments.len() == 1 {
+            segments.insert(0, "**".to_owned());
+        }
+        Ok(Self {
+            source: s.to_owned(),
+            segments,
+        })
+    }
+}
+
+impl TryFrom<String> for InputPattern {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<InputPattern> for String {
+    fn from(value: InputPattern) -> Self {
+        value.source
+    }
+}
+
+impl fmt::Display for InputPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for InputAlias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, target)) = s.split_once('=') else {
+            return Err(format!(
+                "the alias '{s}' is not of the form 'PATTERN=TARGET'"
+            ));
+        };
+        if target.is_empty() || target.contains('/') {
+            return Err(format!(
+                "the alias '{s}' does not name a single root input to follow"
+            ));
+        }
+        Ok(Self {
+            pattern: pattern.parse()?,
+            target: target.to_owned(),
+        })
+    }
+}
+
+// Aliases are written as a table of patterns to targets, in order.
+fn deserialize_aliases<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<InputAlias>, D::Error> {
+    indexmap::IndexMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(pattern, target)| format!("{pattern}={target}").parse())
+        .collect::<Result<_, String>>()
+        .map_err(D::Error::custom)
+}
+
+impl fmt::Display for InputAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.target)
+    }
+}
+
+impl FollowRules {
+    /// Whether no rules are set, so that every input may be redirected
+    /// to the root input of the same name.
+    pub fn is_empty(&self) -> bool {
+        self.exclude.is_empty()
+            && self.only.is_empty()
+            && self.keep.is_empty()
+            && self.aliases.is_empty()
+            && !self.preserve_follows
+    }
+
+    /// The name of the root input which the input at `path` should follow.
+    ///
+    /// The first alias with a scoped pattern matching the path wins,
+    /// otherwise the first alias matching the input name,
+    /// otherwise the input follows the root input of its own name.
+    pub fn target<'a>(&'a self, path: &'a [impl AsRef<str>]) -> &'a str {
+        let matching = |scoped: bool| {
+            self.aliases
+                .iter()
+                .find(|alias| alias.pattern.is_scoped() == scoped && alias.pattern.matches(path))
+        };
+        matching(true).or_else(|| matching(false)).map_or_else(
+            || path.last().map_or("", AsRef::as_ref),
+            |alias| &alias.target,
+        )
+    }
+
+    /// The `follows` path of an input which follows the root input `name`.
+    ///
+    /// That is the name itself, unless the root input is a `follows` alias and
+    /// [`FollowRules::root_aliases`] is [`RootAliases::Target`], then it is the path
+    /// which the chain of aliases ends at, such as `nixpkgs-unstable` for a root
+    /// `nixpkgs` which follows it.
+    pub fn follows_path(&self, lock: &LockFile, name: &str) -> Vec<String> {
+        let mut path = vec![name.to_owned()];
+        if self.root_aliases == RootAliases::Alias {
+            return path;
+        }
+        let root = lock.root().expect(EXPECT_ROOT_EXIST);
+        let mut seen = Vec::new();
+        while let ([name], false) = (path.as_slice(), seen.contains(&path)) {
+            let Some(NodeEdge::Follows(target)) = root.get_edge(name) else {
+                break;
+            };
+            let target = target.clone();
+            seen.push(std::mem::replace(&mut path, target));
+        }
+        path
+    }
+
+    /// Whether the `input` of `node` must not follow the root input `name`, because
+    /// the root input is a `follows` alias which passes through that very input,
+    /// so that the input would follow itself, or which already ends at the node
+    /// the input references, so that there is nothing to redirect.
+    pub fn is_followed_by_root(
+        &self,
+        lock: &LockFile,
+        name: &str,
+        node: NodeId,
+        input: &str,
+    ) -> bool {
+        let root = lock.root().expect(EXPECT_ROOT_EXIST);
+        let Some(NodeEdge::Follows(path)) = root.get_edge(name) else {
+            return false;
+        };
+        let mut passed = vec![(lock.root_id(), name.to_owned())];
+        let target = lock.follow_path_inputs(path, &mut passed);
+        let current = lock
+            .get_node(node)
+            .and_then(|node| node.get_edge(input))
+            .and_then(|edge| lock.resolve_edge(edge));
+        passed.iter().any(|(id, name)| *id == node && name == input)
+            || target.is_some_and(|target| current == Some(target))
+    }
+
+    /// Check whether the input at `path`, belonging to `node`, may be redirected.
+    pub fn check(
+        &self,
+        lock: &LockFile,
+        node: NodeId,
+        path: &[impl AsRef<str>],
+    ) -> Result<(), Exclusion> {
+        if self.keep.iter().any(|index| index == lock.node_index(node)) {
+            return Err(Exclusion::Kept);
+        }
+        if let Some(pattern) = self.exclude.iter().find(|pattern| pattern.matches(path)) {
+            return Err(Exclusion::Excluded(pattern.clone()));
+        }
+        if !self.only.is_empty() && !self.only.iter().any(|pattern| pattern.matches(path)) {
+            return Err(Exclusion::NotOnly);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Exclusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Excluded(pattern) => write!(f, "excluded by '{pattern}'"),
+            Self::NotOnly => write!(f, "not matched by any of the only patterns"),
+            Self::Kept => write!(f, "the node is kept as it is"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_pattern_matches() {
+        let matches = |pattern: &str, path: &str| {
+            let pattern: InputPattern = pattern.parse().unwrap();
+            pattern.matches(&path.split('/').collect::<Vec<_>>())
+        };
+        assert!(matches("hyprland/nixpkgs", "hyprland/nixpkgs"));
+        assert!(!matches("hyprland/nixpkgs", "aquamarine/hyprland/nixpkgs"));
+        assert!(matches("nixpkgs", "nixpkgs"));
+        assert!(matches("nixpkgs", "aquamarine/hyprutils/nixpkgs"));
+        assert!(!matches("nixpkgs", "nixpkgs/systems"));
+        assert!(matches("hypr*/nixpkgs", "hyprlang/nixpkgs"));
+        assert!(matches("*-lib", "hyprland/nixpkgs-lib"));
+        assert!(!matches("hypr*/nixpkgs", "hyprland/hyprlang/nixpkgs"));
+        assert!(matches("hyprland/**", "hyprland/hyprlang/nixpkgs"));
+        assert!(matches("hyprland/**/nixpkgs", "hyprland/nixpkgs"));
+        assert!(!matches("hyprland/**", "aquamarine/nixpkgs"));
+
+        assert!("hyprland//nixpkgs".parse::<InputPattern>().is_err());
+        assert!("".parse::<InputPattern>().is_err());
+    }
+
+    #[test]
+    fn follow_rules_target() {
+        let rules = FollowRules {
+            aliases: [
+                "nixpkgs-lib=nixpkgs",
+                "hyprland/nixpkgs=nixpkgs-unstable",
+                "nixpkgs=nixpkgs-stable",
+            ]
+            .into_iter()
+            .map(|alias| alias.parse().unwrap())
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(rules.target(&["flake-parts", "nixpkgs-lib"]), "nixpkgs");
+        assert_eq!(rules.target(&["hyprland", "nixpkgs"]), "nixpkgs-unstable");
+        assert_eq!(rules.target(&["aquamarine", "nixpkgs"]), "nixpkgs-stable");
+        assert_eq!(rules.target(&["hyprland", "systems"]), "systems");
+
+        assert!("nixpkgs".parse::<InputAlias>().is_err());
+        assert!("nixpkgs=".parse::<InputAlias>().is_err());
+        assert!("utils=a/b".parse::<InputAlias>().is_err());
+    }
+}
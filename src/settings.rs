@@ -0,0 +1,292 @@
+//! Project settings, read from a `.allfollow.toml` next to the `flake.lock`
+//! and overridden by `ALLFOLLOW_*` environment variables.
+//!
+//! ```toml
+//! resolve = "newest"
+//! safety = "strict"
+//! pretty = true
+//!
+//! [rules]
+//! exclude = ["hyprland/nixpkgs"]
+//! keep = ["xdph"]
+//! preserve_follows = true
+//! root_aliases = "alias"
+//!
+//! [rules.aliases]
+//! nixpkgs-lib = "nixpkgs"
+//! "hyprland/nixpkgs" = "nixpkgs-unstable"
+//!
+//! [markers]
+//! start = "# BEGIN FOLLOWS"
+//! end = "# END FOLLOWS"
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::config::Markers;
+use crate::error::{Error, Result, SettingsError};
+use crate::prune::Safety;
+use crate::resolve::ResolvePolicy;
+use crate::rules::FollowRules;
+
+/// The name of the settings file which is looked for next to the `flake.lock`.
+pub const SETTINGS_FILE_NAME: &str = ".allfollow.toml";
+/// The prefix of the environment variables which override the settings.
+pub const ENV_PREFIX: &str = "ALLFOLLOW_";
+/// The environment variable naming a settings file to read instead of discovering one.
+pub const ENV_SETTINGS_FILE: &str = "ALLFOLLOW_CONFIG";
+
+/// Everything which can be set for a project, rather than on the command line.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Which inputs are followed, and which root inputs they follow.
+    /// Options from the command line are added to these.
+    pub rules: FollowRules,
+    /// The policy for `prune --resolve`, unless given on the command line.
+    pub resolve: Option<ResolvePolicy>,
+    /// The mode of `prune --safety`, unless given on the command line.
+    pub safety: Option<Safety>,
+    /// Whether to pretty print JSON output, as if `--pretty` were given.
+    pub pretty: bool,
+    /// The markers of the block of `follows` declarations in the `flake.nix`.
+    pub markers: Markers,
+}
+
+impl Settings {
+    /// The settings file in `dir`, if there is one.
+    pub fn discover(dir: &Path) -> Option<PathBuf> {
+        Some(dir.join(SETTINGS_FILE_NAME)).filter(|path| path.is_file())
+    }
+
+    /// Read the settings of the project in `dir`, see [`Settings::load_with_env`].
+    pub fn load(dir: &Path) -> Result<Self> {
+        Self::load_with_env(dir, std::env::vars())
+    }
+
+    /// Read the file named by [`ENV_SETTINGS_FILE`], otherwise the settings file
+    /// discovered in `dir` if there is one, then apply the overrides of the
+    /// other environment variables in `vars`, see [`Settings::apply_env`].
+    pub fn load_with_env(
+        dir: &Path,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let vars = vars.into_iter().collect::<Vec<_>>();
+        let path = vars
+            .iter()
+            .find(|(key, _)| key == ENV_SETTINGS_FILE)
+            .map(|(_, path)| PathBuf::from(path))
+            .or_else(|| Self::discover(dir));
+        let mut settings = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        settings.apply_env(vars).map_err(Error::Settings)?;
+        Ok(settings)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::io(format!("to read '{}'", path.display()), e))?;
+        Self::from_toml(&content, &format!("'{}'", path.display())).map_err(Error::Settings)
+    }
+
+    /// Parse the settings, where `origin` describes where they came from.
+    pub fn from_toml(content: &str, origin: &str) -> Result<Self, SettingsError> {
+        serde_path_to_error::deserialize(toml::Deserializer::new(content)).map_err(|error| {
+            let key = error.path().to_string();
+            let error = error.into_inner();
+            let line = error
+                .span()
+                .map(|span| content[..span.start].lines().count().max(1));
+            SettingsError {
+                origin: origin.to_owned(),
+                key: if key == "." { String::new() } else { key },
+                message: match line {
+                    Some(line) => format!("{} (at line {line})", error.message()),
+                    None => error.message().to_owned(),
+                },
+            }
+        })
+    }
+
+    /// Override the settings with the `ALLFOLLOW_*` variables among `vars`.
+    ///
+    /// Lists are comma separated and replace the lists of the settings file:
+    /// `ALLFOLLOW_EXCLUDE`, `ALLFOLLOW_ONLY`, `ALLFOLLOW_KEEP`, and `ALLFOLLOW_ALIASES`
+    /// of `PATTERN=TARGET` pairs. The others are `ALLFOLLOW_PRESERVE_FOLLOWS`,
+    /// `ALLFOLLOW_ROOT_ALIASES`, `ALLFOLLOW_RESOLVE`, `ALLFOLLOW_SAFETY`, `ALLFOLLOW_PRETTY`,
+    /// `ALLFOLLOW_START_MARKER` and `ALLFOLLOW_END_MARKER`.
+    /// Any other variable with the prefix, save for [`ENV_SETTINGS_FILE`], is an error.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), SettingsError> {
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let applied = match name {
+                "CONFIG" => Ok(()),
+                "EXCLUDE" => parse_list(&value).map(|list| self.rules.exclude = list),
+                "ONLY" => parse_list(&value).map(|list| self.rules.only = list),
+                "KEEP" => parse_list(&value).map(|list| self.rules.keep = list),
+                "ALIASES" => parse_list(&value).map(|list| self.rules.aliases = list),
+                "RESOLVE" => value.parse().map(|policy| self.resolve = Some(policy)),
+                "SAFETY" => value.parse().map(|safety| self.safety = Some(safety)),
+                "PRETTY" => parse_bool(&value).map(|pretty| self.pretty = pretty),
+                "PRESERVE_FOLLOWS" => parse_bool(&value)
+                    .map(|preserve_follows| self.rules.preserve_follows = preserve_follows),
+                "ROOT_ALIASES" => value.parse().map(|mode| self.rules.root_aliases = mode),
+                "START_MARKER" => {
+                    self.markers.start = value;
+                    Ok(())
+                }
+                "END_MARKER" => {
+                    self.markers.end = value;
+                    Ok(())
+                }
+                _ => Err("not a known setting".to_owned()),
+            };
+            applied.map_err(|message| SettingsError {
+                origin: "the environment".to_owned(),
+                key,
+                message,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_list<T>(value: &str) -> Result<Vec<T>, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(|e: T::Err| e.to_string()))
+        .collect()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" | "" => Ok(false),
+        _ => Err(format!("expected 'true' or 'false', found '{value}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_settings() {
+        let settings = Settings::from_toml(
+            r##"
+            resolve = "newest"
+
+            [rules]
+            exclude = ["hyprland/nixpkgs"]
+
+            [rules.aliases]
+            utils = "flake-utils"
+            "hyprland/nixpkgs" = "nixpkgs-unstable"
+
+            [markers]
+            start = "# BEGIN"
+            "##,
+            "test",
+        )
+        .unwrap();
+        assert_eq!(settings.resolve, Some(ResolvePolicy::Newest));
+        assert_eq!(
+            settings.rules.exclude,
+            ["hyprland/nixpkgs".parse().unwrap()]
+        );
+        assert_eq!(settings.rules.target(&["a", "utils"]), "flake-utils");
+        assert_eq!(
+            settings.rules.target(&["hyprland", "nixpkgs"]),
+            "nixpkgs-unstable"
+        );
+        assert_eq!(settings.markers.start, "# BEGIN");
+        assert_eq!(settings.markers.end, crate::config::END_MARKER);
+        assert!(!settings.pretty);
+    }
+
+    #[test]
+    fn settings_errors_name_the_key() {
+        let key = |content: &str| Settings::from_toml(content, "test").unwrap_err().key;
+        assert_eq!(
+            key("[rules]\nexclude = [\"a\", \"b//c\"]"),
+            "rules.exclude[1]"
+        );
+        assert_eq!(key("resolve = \"latest\""), "resolve");
+        assert_eq!(key("[rules]\nfollow = []"), "rules.follow");
+        assert_eq!(key("[rules.aliases]\nutils = \"\""), "rules.aliases");
+        assert_eq!(key("pretty = "), "");
+
+        let error = Settings::default()
+            .apply_env(vars(&[("ALLFOLLOW_RESOLVE", "latest")]))
+            .unwrap_err();
+        assert_eq!(error.key, "ALLFOLLOW_RESOLVE");
+        assert_eq!(error.origin, "the environment");
+        let error = Settings::default()
+            .apply_env(vars(&[("ALLFOLLOW_EXCLUDES", "a")]))
+            .unwrap_err();
+        assert_eq!(error.key, "ALLFOLLOW_EXCLUDES");
+    }
+
+    #[test]
+    fn load_settings_with_env() {
+        let dir = std::env::temp_dir().join("allfollow_test_settings");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join(SETTINGS_FILE_NAME),
+            "pretty = true\n[rules]\nexclude = [\"a\"]\nkeep = [\"b\"]\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_with_env(
+            &dir,
+            vars(&[
+                ("ALLFOLLOW_EXCLUDE", "c,d/e"),
+                ("ALLFOLLOW_ALIASES", "utils=flake-utils"),
+                ("ALLFOLLOW_PRETTY", "false"),
+                ("HOME", "/"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            settings.rules.exclude,
+            ["c".parse().unwrap(), "d/e".parse().unwrap()]
+        );
+        assert_eq!(settings.rules.keep, ["b"]);
+        assert_eq!(settings.rules.target(&["utils"]), "flake-utils");
+        assert!(!settings.pretty);
+
+        let error = Settings::load_with_env(
+            &dir,
+            vars(&[("ALLFOLLOW_CONFIG", "/nonexistent/.allfollow.toml")]),
+        )
+        .unwrap_err();
+        assert_eq!(error.exit_code(), Error::EXIT_IO);
+
+        let empty = dir.join("empty");
+        std::fs::create_dir_all(&empty).unwrap();
+        assert_eq!(
+            Settings::load_with_env(&empty, []).unwrap(),
+            Settings::default()
+        );
+    }
+}
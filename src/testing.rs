@@ -0,0 +1,66 @@
+//! Samples and lock builders shared by the tests of every module.
+
+use serde_json::{json, Value};
+
+use crate::flake_lock::LockFile;
+use crate::prune::{
+    normalize_follows_paths, prune_orphan_nodes, substitute_flake_inputs_with_follows,
+    SubstituteOptions,
+};
+
+pub static HYPRLAND_LOCK_NO_FOLLOWS: &str = "samples/hyprland/no-follows/flake.lock";
+pub static HYPRLAND_LOCK_WITH_FOLLOWS: &str = "samples/hyprland/with-follows/flake.lock";
+pub static NIXPKGS_FOLLOWS_LOCK: &str = "samples/nixpkgs-follows-nixpkgs-unstable/flake.lock";
+
+pub fn read_sample(path: &str) -> LockFile {
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+/// The sample where the root `nixpkgs` follows `nixpkgs-unstable`,
+/// with a `home-manager` input which locks its own `nixpkgs`.
+pub fn read_nixpkgs_follows_sample_with_home_manager() -> LockFile {
+    let mut lock: Value =
+        serde_json::from_str(&std::fs::read_to_string(NIXPKGS_FOLLOWS_LOCK).unwrap()).unwrap();
+    let nodes = lock["nodes"].as_object_mut().unwrap();
+    let mut nixpkgs = nodes["nixpkgs-unstable"].clone();
+    nixpkgs["locked"]["rev"] = "0000000000000000000000000000000000000000".into();
+    nodes.insert("nixpkgs".to_owned(), nixpkgs);
+    nodes.insert(
+        "home-manager".to_owned(),
+        json!({
+            "inputs": {"nixpkgs": "nixpkgs"},
+            "locked": {"owner": "nix-community", "repo": "home-manager", "type": "github"},
+            "original": {"owner": "nix-community", "repo": "home-manager", "type": "github"}
+        }),
+    );
+    nodes["root"]["inputs"]["home-manager"] = "home-manager".into();
+    serde_json::from_value(lock).unwrap()
+}
+
+/// A node locking `github:o/{repo}`, with the `inputs` as written in a lock file.
+pub fn github_node(repo: &str, inputs: Value) -> Value {
+    json!({
+        "inputs": inputs,
+        "locked": {"owner": "o", "repo": repo, "type": "github"},
+        "original": {"owner": "o", "repo": repo, "type": "github"}
+    })
+}
+
+/// A lock of the `nodes`, one of which is the root node `root`.
+pub fn lock_of(nodes: Value) -> LockFile {
+    serde_json::from_value(json!({
+        "nodes": nodes,
+        "root": "root",
+        "version": 7
+    }))
+    .unwrap()
+}
+
+/// Prune the `lock` in the steps which `allfollow prune` takes without
+/// `--resolve`, `--hoist` or `--dedupe`, so that snapshots match what it writes.
+pub fn prune(lock: &mut LockFile, options: &SubstituteOptions) {
+    let before = lock.clone();
+    let substitutions = substitute_flake_inputs_with_follows(lock, options);
+    prune_orphan_nodes(lock);
+    normalize_follows_paths(lock, &before, &substitutions, &options.rules).unwrap();
+}